@@ -0,0 +1,84 @@
+//! Selector dispatch for contracts holding a whole module.
+//!
+//! Calldata is a 4-byte selector followed by one 32-byte word per parameter.
+use etk_asm::ops::{AbstractOp, Imm, Op};
+use revm_primitives::keccak256;
+use wain_ast::{FuncType, ValType};
+
+/// How the 4-byte selector of an exported function is derived.
+#[derive(Debug, Clone, Copy)]
+pub enum Selector {
+    /// First 4 bytes of the keccak256 of `name(uint32,uint64,...)`, like Solidity.
+    Keccak,
+    /// The index of the function in the module.
+    Index,
+}
+
+/// Bytes of calldata taken by the selector.
+pub const SELECTOR_SIZE: u32 = 4;
+
+impl Selector {
+    pub fn selector(&self, name: &str, idx: u32, ty: &FuncType) -> u32 {
+        match self {
+            Selector::Keccak => {
+                let hash = keccak256(signature(name, ty).as_bytes());
+                u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]])
+            }
+            Selector::Index => idx,
+        }
+    }
+}
+
+/// Solidity-style signature of an exported function.
+pub fn signature(name: &str, ty: &FuncType) -> String {
+    let params: Vec<&str> = ty
+        .params
+        .iter()
+        .map(|param| match param {
+            ValType::I32 => "uint32",
+            ValType::I64 => "uint64",
+            other => other.as_ref(),
+        })
+        .collect();
+    format!("{}({})", name, params.join(","))
+}
+
+pub fn dispatch_label(idx: u32) -> String {
+    format!("dispatch_{}", idx)
+}
+
+/// Where calls whose calldata matches no selector end up.
+const NO_MATCH_LABEL: &str = "dispatch_no_match";
+
+/// Jumps to `dispatch_label(idx)` with the selector on the stack for the function
+/// whose selector matches the calldata, and reverts if none does or the calldata
+/// is too short to hold a selector.
+pub fn dispatcher(selectors: &[(u32, u32)]) -> Vec<AbstractOp> {
+    let mut result = vec![
+        AbstractOp::Op(Op::Push1(Imm::from(SELECTOR_SIZE as u8))),
+        AbstractOp::Op(Op::CallDataSize),
+        AbstractOp::Op(Op::Lt),
+        AbstractOp::Op(Op::Push2(Imm::with_label(NO_MATCH_LABEL))),
+        AbstractOp::Op(Op::JumpI),
+        AbstractOp::Op(Op::Push1(Imm::from(0_u8))),
+        AbstractOp::Op(Op::CallDataLoad),
+        AbstractOp::Op(Op::Push1(Imm::from(0xe0_u8))),
+        AbstractOp::Op(Op::Shr),
+    ];
+
+    for (idx, selector) in selectors {
+        result.push(AbstractOp::Op(Op::Dup1));
+        result.push(AbstractOp::Op(Op::Push4(Imm::from(*selector))));
+        result.push(AbstractOp::Op(Op::Eq));
+        result.push(AbstractOp::Op(Op::Push2(Imm::with_label(dispatch_label(*idx)))));
+        result.push(AbstractOp::Op(Op::JumpI));
+    }
+
+    result.push(AbstractOp::Label(NO_MATCH_LABEL.to_string()));
+    result.push(AbstractOp::Op(Op::JumpDest));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(0_u8))));
+    result.push(AbstractOp::Op(Op::Dup1));
+    result.push(AbstractOp::Op(Op::Revert));
+
+    result
+}
@@ -0,0 +1,633 @@
+//! Imported functions bound to inline EVM code.
+//!
+//! A call to an import is replaced by the code of its binding, which finds the
+//! Wasm arguments on the stack, last one on top, and leaves the results there.
+//! Values wider than 64 bits, like addresses and hashes, are passed through
+//! linear memory as 32-byte big-endian words.
+use super::{
+    memory_address, memory_bounds_check, swap, Context, BYTES8, PAGE_BITS, TRAP_LABEL,
+};
+use crate::info::ModuleInfo;
+use crate::layout;
+use etk_asm::ops::{AbstractOp, Imm, Op};
+use std::collections::HashMap;
+use wain_ast::{FuncKind, FuncType, GlobalKind, Mem, ValType};
+use wain_exec::trap::{Result, Trap, TrapReason};
+
+/// Inline code standing in for an imported function of the given signature.
+#[derive(Debug, Clone, Copy)]
+pub struct HostFunc {
+    pub params: &'static [ValType],
+    pub results: &'static [ValType],
+    pub emit: fn(&Context) -> Vec<AbstractOp>,
+}
+
+impl HostFunc {
+    fn matches(&self, ty: &FuncType) -> bool {
+        self.params == ty.params.as_slice() && self.results == ty.results.as_slice()
+    }
+}
+
+/// Bindings from the module and name of an import to the code replacing it.
+#[derive(Debug)]
+pub struct Imports {
+    funcs: HashMap<(String, String), HostFunc>,
+}
+
+impl Imports {
+    /// No bindings at all.
+    pub fn new() -> Self {
+        Imports {
+            funcs: HashMap::new(),
+        }
+    }
+
+    pub fn bind(&mut self, mod_name: &str, name: &str, func: HostFunc) -> &mut Self {
+        self.funcs
+            .insert((mod_name.to_string(), name.to_string()), func);
+        self
+    }
+
+    pub fn get(&self, mod_name: &str, name: &str) -> Option<&HostFunc> {
+        self.funcs.get(&(mod_name.to_string(), name.to_string()))
+    }
+
+    /// Fails on the first imported function of the module that has no binding or
+    /// whose binding has another signature. Globals cannot be bound, so any
+    /// imported global fails too.
+    pub fn check(&self, info: &ModuleInfo) -> Result<()> {
+        for (idx, func) in info.funcs() {
+            if let FuncKind::Import(import) = &func.kind {
+                let mod_name = import.mod_name.0.to_string();
+                let name = import.name.0.to_string();
+                let ty = info.func_type(idx);
+                let reason = match self.get(&mod_name, &name) {
+                    None => TrapReason::UnknownImport {
+                        mod_name,
+                        name,
+                        kind: "function",
+                    },
+                    Some(host) if !host.matches(ty) => TrapReason::FuncSignatureMismatch {
+                        import: Some((mod_name, name)),
+                        expected_params: host.params.into(),
+                        expected_results: host.results.into(),
+                        actual_params: ty.params.clone().into_boxed_slice(),
+                        actual_results: ty.results.clone().into_boxed_slice(),
+                    },
+                    Some(_) => continue,
+                };
+                return Err(Box::new(Trap {
+                    reason,
+                    offset: func.start,
+                }));
+            }
+        }
+
+        for global in &info.module.globals {
+            if let GlobalKind::Import(import) = &global.kind {
+                return Err(Box::new(Trap {
+                    reason: TrapReason::UnknownImport {
+                        mod_name: import.mod_name.0.to_string(),
+                        name: import.name.0.to_string(),
+                        kind: "global",
+                    },
+                    offset: global.start,
+                }));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Imports {
+    /// The `env` functions defined in this module.
+    fn default() -> Self {
+        let mut imports = Imports::new();
+        imports
+            .bind("env", "caller", HostFunc {
+                params: &[ValType::I32],
+                results: &[],
+                emit: caller,
+            })
+            .bind("env", "sload", HostFunc {
+                params: &[ValType::I64],
+                results: &[ValType::I64],
+                emit: sload,
+            })
+            .bind("env", "sstore", HostFunc {
+                params: &[ValType::I64, ValType::I64],
+                results: &[],
+                emit: sstore,
+            })
+            .bind("env", "storage_load", HostFunc {
+                params: &[ValType::I32, ValType::I32],
+                results: &[],
+                emit: storage_load,
+            })
+            .bind("env", "storage_store", HostFunc {
+                params: &[ValType::I32, ValType::I32],
+                results: &[],
+                emit: storage_store,
+            })
+            .bind("env", "calldata_size", HostFunc {
+                params: &[],
+                results: &[ValType::I32],
+                emit: calldata_size,
+            })
+            .bind("env", "calldata_copy", HostFunc {
+                params: &[ValType::I32, ValType::I32, ValType::I32],
+                results: &[],
+                emit: calldata_copy,
+            })
+            .bind("env", "return_data", HostFunc {
+                params: &[ValType::I32, ValType::I32],
+                results: &[],
+                emit: return_data,
+            })
+            .bind("env", "revert", HostFunc {
+                params: &[ValType::I32, ValType::I32],
+                results: &[],
+                emit: revert,
+            })
+            .bind("env", "log", HostFunc {
+                params: &[ValType::I32, ValType::I32],
+                results: &[],
+                emit: log0,
+            })
+            .bind("env", "log0", HostFunc {
+                params: &[ValType::I32, ValType::I32],
+                results: &[],
+                emit: log0,
+            })
+            .bind("env", "log1", HostFunc {
+                params: &[ValType::I32, ValType::I32, ValType::I32],
+                results: &[],
+                emit: log1,
+            })
+            .bind("env", "log2", HostFunc {
+                params: &[ValType::I32, ValType::I32, ValType::I32, ValType::I32],
+                results: &[],
+                emit: log2,
+            })
+            .bind("env", "log3", HostFunc {
+                params: &[ValType::I32; 5],
+                results: &[],
+                emit: log3,
+            })
+            .bind("env", "log4", HostFunc {
+                params: &[ValType::I32; 6],
+                results: &[],
+                emit: log4,
+            })
+            .bind("env", "balance", HostFunc {
+                params: &[ValType::I32, ValType::I32],
+                results: &[],
+                emit: balance,
+            })
+            .bind("env", "keccak256", HostFunc {
+                params: &[ValType::I32, ValType::I32, ValType::I32],
+                results: &[],
+                emit: keccak256,
+            });
+        imports
+    }
+}
+
+/// Turns the Wasm address `[ptr]` of a 32-byte word into its EVM memory offset,
+/// trapping if the word is not inside the linear memory.
+pub fn word_address(context: &Context) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+    let mem = Mem {
+        align: 0,
+        offset: 0,
+    };
+
+    result.append(memory_bounds_check(&mem, layout::WORD as u8).as_mut());
+    result.append(memory_address(context, &mem).as_mut());
+
+    result
+}
+
+/// Turns the Wasm range `[ptr, len]` into `[len, offset]` with the EVM memory
+/// offset on top, trapping if the range is not inside the linear memory.
+pub fn range_address(context: &Context) -> Vec<AbstractOp> {
+    let mut result = vec![
+        AbstractOp::Op(Op::Dup2),
+        AbstractOp::Op(Op::Dup2),
+        AbstractOp::Op(Op::Add),
+        AbstractOp::Op(Op::Push1(Imm::from(layout::PAGES as u8))),
+        AbstractOp::Op(Op::MLoad),
+        AbstractOp::Op(Op::Push1(Imm::from(PAGE_BITS))),
+        AbstractOp::Op(Op::Shl),
+        AbstractOp::Op(Op::Lt),
+        AbstractOp::Op(Op::Push2(Imm::with_label(TRAP_LABEL))),
+        AbstractOp::Op(Op::JumpI),
+    ];
+
+    result.push(AbstractOp::Op(Op::Swap1));
+    result.push(AbstractOp::Op(Op::Push8(Imm::from(context.layout.linear_memory))));
+    result.push(AbstractOp::Op(Op::Add));
+
+    result
+}
+
+/// `caller(out_ptr)` writes the address of the caller.
+fn caller(context: &Context) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.append(word_address(context).as_mut());
+    result.push(AbstractOp::Op(Op::Caller));
+    result.push(AbstractOp::Op(Op::Swap1));
+    result.push(AbstractOp::Op(Op::MStore));
+
+    result
+}
+
+/// `sload(key) -> value`, keeping the low 64 bits of the slot.
+fn sload(_context: &Context) -> Vec<AbstractOp> {
+    vec![
+        AbstractOp::Op(Op::SLoad),
+        AbstractOp::Op(Op::Push8(Imm::from(BYTES8))),
+        AbstractOp::Op(Op::And),
+    ]
+}
+
+/// `sstore(key, value)`
+fn sstore(_context: &Context) -> Vec<AbstractOp> {
+    vec![
+        AbstractOp::Op(Op::Swap1),
+        AbstractOp::Op(Op::SStore),
+    ]
+}
+
+/// `storage_load(key_ptr, out_ptr)` writes the storage word under the key at `key_ptr`.
+fn storage_load(context: &Context) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.push(AbstractOp::Op(Op::Swap1));
+    result.append(word_address(context).as_mut());
+    result.push(AbstractOp::Op(Op::MLoad));
+    result.push(AbstractOp::Op(Op::SLoad));
+    result.push(AbstractOp::Op(Op::Swap1));
+    result.append(word_address(context).as_mut());
+    result.push(AbstractOp::Op(Op::MStore));
+
+    result
+}
+
+/// `storage_store(key_ptr, val_ptr)` stores the word at `val_ptr` under the key at `key_ptr`.
+fn storage_store(context: &Context) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.append(word_address(context).as_mut());
+    result.push(AbstractOp::Op(Op::MLoad));
+    result.push(AbstractOp::Op(Op::Swap1));
+    result.append(word_address(context).as_mut());
+    result.push(AbstractOp::Op(Op::MLoad));
+    result.push(AbstractOp::Op(Op::SStore));
+
+    result
+}
+
+/// `calldata_size() -> len` counts the whole calldata, selector included.
+fn calldata_size(_context: &Context) -> Vec<AbstractOp> {
+    vec![
+        AbstractOp::Op(Op::CallDataSize),
+    ]
+}
+
+/// `calldata_copy(dst, offset, len)` copies calldata bytes into linear memory,
+/// padding with zeros past the end of the calldata.
+fn calldata_copy(context: &Context) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.push(AbstractOp::Op(Op::Swap2));
+    result.push(AbstractOp::Op(Op::Dup3));
+    result.append(range_address(context).as_mut());
+    result.push(AbstractOp::Op(Op::Swap1));
+    result.push(AbstractOp::Op(Op::Pop));
+    result.push(AbstractOp::Op(Op::CallDataCopy));
+
+    result
+}
+
+/// `return_data(ptr, len)` ends the call successfully with the bytes as output.
+fn return_data(context: &Context) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.append(range_address(context).as_mut());
+    result.push(AbstractOp::Op(Op::Return));
+
+    result
+}
+
+/// `revert(ptr, len)` reverts the call with the bytes as output.
+fn revert(context: &Context) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.append(range_address(context).as_mut());
+    result.push(AbstractOp::Op(Op::Revert));
+
+    result
+}
+
+/// `logN(ptr, len, topic_ptr_1, ..., topic_ptr_N)` emits the bytes as a log
+/// with the N words at the topic pointers as topics.
+fn log(context: &Context, topics: u8) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    // replace each topic pointer by its word, the last one is on top
+    for depth in 0..topics {
+        if depth > 0 {
+            result.push(AbstractOp::Op(swap(depth as usize)));
+        }
+        result.append(word_address(context).as_mut());
+        result.push(AbstractOp::Op(Op::MLoad));
+        if depth > 0 {
+            result.push(AbstractOp::Op(swap(depth as usize)));
+        }
+    }
+
+    // LOGn wants [topic_N, ..., topic_1, len, ptr], the reverse of the params
+    let params = topics + 2;
+    for depth in 0..params / 2 {
+        let other = params - 1 - depth;
+        if depth > 0 {
+            result.push(AbstractOp::Op(swap(depth as usize)));
+        }
+        result.push(AbstractOp::Op(swap(other as usize)));
+        if depth > 0 {
+            result.push(AbstractOp::Op(swap(depth as usize)));
+        }
+    }
+    result.push(AbstractOp::Op(Op::Swap1));
+    result.append(range_address(context).as_mut());
+
+    result.push(AbstractOp::Op(match topics {
+        0 => Op::Log0,
+        1 => Op::Log1,
+        2 => Op::Log2,
+        3 => Op::Log3,
+        _ => Op::Log4,
+    }));
+
+    result
+}
+
+fn log0(context: &Context) -> Vec<AbstractOp> {
+    log(context, 0)
+}
+
+fn log1(context: &Context) -> Vec<AbstractOp> {
+    log(context, 1)
+}
+
+fn log2(context: &Context) -> Vec<AbstractOp> {
+    log(context, 2)
+}
+
+fn log3(context: &Context) -> Vec<AbstractOp> {
+    log(context, 3)
+}
+
+fn log4(context: &Context) -> Vec<AbstractOp> {
+    log(context, 4)
+}
+
+/// `balance(addr_ptr, out_ptr)` writes the balance of the address at `addr_ptr`.
+fn balance(context: &Context) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.push(AbstractOp::Op(Op::Swap1));
+    result.append(word_address(context).as_mut());
+    result.push(AbstractOp::Op(Op::MLoad));
+    result.push(AbstractOp::Op(Op::Balance));
+    result.push(AbstractOp::Op(Op::Swap1));
+    result.append(word_address(context).as_mut());
+    result.push(AbstractOp::Op(Op::MStore));
+
+    result
+}
+
+/// `keccak256(ptr, len, out_ptr)` writes the hash of the bytes.
+fn keccak256(context: &Context) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.push(AbstractOp::Op(Op::Swap2));
+    result.push(AbstractOp::Op(Op::Swap1));
+    result.append(range_address(context).as_mut());
+    result.push(AbstractOp::Op(Op::Keccak256));
+    result.push(AbstractOp::Op(Op::Swap1));
+    result.append(word_address(context).as_mut());
+    result.push(AbstractOp::Op(Op::MStore));
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{abi, revm_run, Deployment, Globals, Runner, Value};
+    use primitive_types::H256;
+    use revm_primitives::{ExecutionResult, Output};
+    use wain_syntax_binary::parse;
+
+    /// `set(key, value)` stores `value` under the word `key` and returns it,
+    /// `get(key)` reads it back.
+    const STORAGE: &str = r#"(module
+      (import "env" "storage_load" (func $load (param i32 i32)))
+      (import "env" "storage_store" (func $store (param i32 i32)))
+      (memory 1)
+      (func (export "set") (param i64 i64) (result i64)
+        i32.const 24 local.get 0 i64.store
+        i32.const 56 local.get 1 i64.store
+        i32.const 0 i32.const 32 call $store
+        local.get 1)
+      (func (export "get") (param i64) (result i64)
+        i32.const 24 local.get 0 i64.store
+        i32.const 0 i32.const 32 call $load
+        i32.const 56 i64.load))"#;
+
+    fn output(result: &ExecutionResult) -> Vec<u8> {
+        match result {
+            ExecutionResult::Success {
+                output: Output::Call(bytes),
+                ..
+            } => bytes.to_vec(),
+            other => panic!("call failed: {:?}", other),
+        }
+    }
+
+    fn word(value: u64) -> String {
+        format!("{:064x}", value)
+    }
+
+    #[test]
+    fn storage_survives_across_calls() {
+        let binary = wat::parse_str(STORAGE).unwrap();
+        let tree = parse(&binary).ok().unwrap();
+        let runner = Runner::instantiate_with(
+            &tree.module,
+            Deployment::Module(abi::Selector::Keccak),
+            Globals::Memory,
+            Imports::default(),
+        )
+        .ok()
+        .unwrap();
+        let address = runner.functions["set"].clone();
+        let set = format!("{:08x}", runner.selectors["set"]);
+        let get = format!("{:08x}", runner.selectors["get"]);
+
+        let mut db = runner.db.clone();
+        for (key, value) in [(1, 0x1122334455667788), (2, 42), (1, 7)] {
+            let data = set.clone() + &word(key) + &word(value);
+            let (result, next) = revm_run::call_contract(address.clone(), data, db);
+            output(&result);
+            db = next.unwrap();
+        }
+
+        for (key, value) in [(1, 7), (2, 42), (3, 0)] {
+            let data = get.clone() + &word(key);
+            let (result, next) = revm_run::call_contract(address.clone(), data, db);
+            assert_eq!(output(&result), hex::decode(word(value)).unwrap());
+            db = next.unwrap();
+        }
+    }
+
+    #[test]
+    fn storage_is_visible_to_later_invocations() {
+        let binary = wat::parse_str(STORAGE).unwrap();
+        let tree = parse(&binary).ok().unwrap();
+        let mut runner = Runner::instantiate_with(
+            &tree.module,
+            Deployment::Module(abi::Selector::Index),
+            Globals::Memory,
+            Imports::default(),
+        )
+        .ok()
+        .unwrap();
+
+        runner.invoke("set", &[Value::U64(9), Value::U64(99)]).unwrap();
+        let result = runner.invoke("get", &[Value::U64(9)]).unwrap();
+        assert_eq!(output(&result), hex::decode(word(99)).unwrap());
+    }
+
+    #[test]
+    fn stored_globals_stay_clear_of_host_storage() {
+        let source = r#"(module
+          (import "env" "sload" (func $sload (param i64) (result i64)))
+          (import "env" "sstore" (func $sstore (param i64 i64)))
+          (global $sp (mut i32) (i32.const 1024))
+          (func (export "poke") (result i32)
+            i64.const 0 i64.const 5 call $sstore
+            global.get $sp)
+          (func (export "bump") (result i32)
+            global.get $sp i32.const 16 i32.sub global.set $sp
+            global.get $sp)
+          (func (export "peek") (result i64) i64.const 0 call $sload))"#;
+        let binary = wat::parse_str(source).unwrap();
+        let tree = parse(&binary).ok().unwrap();
+        let mut runner = Runner::instantiate_with(
+            &tree.module,
+            Deployment::Module(abi::Selector::Keccak),
+            Globals::Storage(vec![0]),
+            Imports::default(),
+        )
+        .ok()
+        .unwrap();
+
+        let result = runner.invoke("poke", &[]).unwrap();
+        assert_eq!(output(&result), hex::decode(word(1024)).unwrap());
+        runner.invoke("bump", &[]).unwrap();
+        let result = runner.invoke("bump", &[]).unwrap();
+        assert_eq!(output(&result), hex::decode(word(992)).unwrap());
+        let result = runner.invoke("peek", &[]).unwrap();
+        assert_eq!(output(&result), hex::decode(word(5)).unwrap());
+    }
+
+    #[test]
+    fn imported_globals_are_rejected() {
+        let source = r#"(module
+          (import "env" "g" (global $g (mut i32)))
+          (func (export "f") (result i32) global.get $g))"#;
+        let binary = wat::parse_str(source).unwrap();
+        let tree = parse(&binary).ok().unwrap();
+        match Runner::instantiate(&tree.module) {
+            Err(trap) => assert!(matches!(
+                trap.reason,
+                TrapReason::UnknownImport { kind: "global", .. }
+            )),
+            Ok(_) => panic!("an unbound global was read as zero"),
+        }
+    }
+
+    #[test]
+    fn logs_carry_their_topics_in_order() {
+        let source = r#"(module
+          (import "env" "log0" (func $log0 (param i32 i32)))
+          (import "env" "log2" (func $log2 (param i32 i32 i32 i32)))
+          (import "env" "log4" (func $log4 (param i32 i32 i32 i32 i32 i32)))
+          (memory 1)
+          (data (i32.const 0) "\01") (data (i32.const 32) "\02")
+          (data (i32.const 64) "\03") (data (i32.const 96) "\04")
+          (data (i32.const 128) "hello")
+          (func (export "emit") (param i32) (result i32)
+            i32.const 128 i32.const 5 call $log0
+            i32.const 128 local.get 0 i32.const 0 i32.const 32 call $log2
+            i32.const 130 i32.const 3 i32.const 96 i32.const 64 i32.const 32 i32.const 0 call $log4
+            local.get 0))"#;
+        let binary = wat::parse_str(source).unwrap();
+        let tree = parse(&binary).ok().unwrap();
+        let mut runner = Runner::instantiate(&tree.module).ok().unwrap();
+
+        let (_, events) = runner.invoke_with_events("emit", &[Value::U32(2)]).unwrap();
+        let topic = |first: u8| {
+            let mut word = [0; 32];
+            word[0] = first;
+            H256(word)
+        };
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].topics, vec![]);
+        assert_eq!(events[0].data, b"hello");
+        assert_eq!(events[1].topics, vec![topic(1), topic(2)]);
+        assert_eq!(events[1].data, b"he");
+        assert_eq!(events[2].topics, vec![topic(4), topic(3), topic(2), topic(1)]);
+        assert_eq!(events[2].data, b"llo");
+        assert_eq!(events[0].address, runner.functions["emit"]);
+    }
+
+    #[test]
+    fn byte_payloads_in_and_out() {
+        // returns the calldata after its first byte, or reverts with it when that byte is 0
+        let source = r#"(module
+          (import "env" "calldata_size" (func $size (result i32)))
+          (import "env" "calldata_copy" (func $copy (param i32 i32 i32)))
+          (import "env" "return_data" (func $return (param i32 i32)))
+          (import "env" "revert" (func $revert (param i32 i32)))
+          (memory 1)
+          (func (export "echo") (result i32)
+            i32.const 16 i32.const 1 call $size i32.const 1 i32.sub call $copy
+            i32.const 0 i32.const 0 i32.const 1 call $copy
+            i32.const 0 i32.load8_u i32.eqz if
+              i32.const 16 call $size i32.const 1 i32.sub call $revert
+            end
+            i32.const 16 call $size i32.const 1 i32.sub call $return
+            unreachable))"#;
+        let binary = wat::parse_str(source).unwrap();
+        let tree = parse(&binary).ok().unwrap();
+        let runner = Runner::instantiate(&tree.module).ok().unwrap();
+        let address = runner.functions["echo"].clone();
+
+        let payload = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff0102";
+        let data = "01".to_string() + payload;
+        let (result, _) = revm_run::call_contract(address.clone(), data, runner.db.clone());
+        assert_eq!(output(&result), hex::decode(payload).unwrap());
+
+        let (result, _) = revm_run::call_contract(address, "00abcd".to_string(), runner.db);
+        match result {
+            ExecutionResult::Revert { output, .. } => assert_eq!(output.to_vec(), vec![0xab, 0xcd]),
+            other => panic!("expected a revert: {:?}", other),
+        }
+    }
+}
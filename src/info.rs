@@ -0,0 +1,76 @@
+//! Index spaces of a module.
+//!
+//! Imported functions come first in the function index space, followed by the
+//! functions the module defines. The signature of a function is found through
+//! the type index it declares, which has nothing to do with its own index.
+use wain_ast::{Func, FuncKind, FuncType, Module};
+
+#[derive(Debug, Clone, Copy)]
+pub struct ModuleInfo<'a, 's> {
+    pub module: &'a Module<'s>,
+    /// Number of imported functions, which take the lowest function indices.
+    pub imported_funcs: u32,
+}
+
+impl<'a, 's> ModuleInfo<'a, 's> {
+    pub fn new(module: &'a Module<'s>) -> Self {
+        let imported_funcs = module
+            .funcs
+            .iter()
+            .take_while(|func| matches!(func.kind, FuncKind::Import(_)))
+            .count();
+        assert!(
+            module.funcs[imported_funcs..]
+                .iter()
+                .all(|func| matches!(func.kind, FuncKind::Body { .. })),
+            "imported functions must come before the defined ones"
+        );
+
+        ModuleInfo {
+            module,
+            imported_funcs: imported_funcs as u32,
+        }
+    }
+
+    /// Function `idx` of the function index space.
+    pub fn func(&self, idx: u32) -> &'a Func<'s> {
+        &self.module.funcs[idx as usize]
+    }
+
+    pub fn is_import(&self, idx: u32) -> bool {
+        idx < self.imported_funcs
+    }
+
+    /// Type `typeidx` of the type section.
+    pub fn ty(&self, typeidx: u32) -> &'a FuncType {
+        &self.module.types[typeidx as usize]
+    }
+
+    /// Signature of function `idx`.
+    pub fn func_type(&self, idx: u32) -> &'a FuncType {
+        self.ty(self.func(idx).idx)
+    }
+
+    /// Functions with their index, imports first.
+    pub fn funcs(&self) -> impl Iterator<Item = (u32, &'a Func<'s>)> {
+        self.module
+            .funcs
+            .iter()
+            .enumerate()
+            .map(|(idx, func)| (idx as u32, func))
+    }
+
+    /// First type with the same signature as type `typeidx`, which stands for
+    /// that signature when signatures are compared at runtime. Modules with
+    /// more types than fit in a `u16` are rejected before code generation.
+    pub fn canonical_type(&self, typeidx: u32) -> u16 {
+        let ty = self.ty(typeidx);
+        let canonical = self
+            .module
+            .types
+            .iter()
+            .position(|other| other.params == ty.params && other.results == ty.results)
+            .unwrap();
+        u16::try_from(canonical).expect("type index past the type limit")
+    }
+}
@@ -0,0 +1,87 @@
+//! EVM memory layout of a compiled module.
+//!
+//! ```text
+//! 0x00              scratch: return buffer and short-lived temporaries
+//! 0x40              reserved slots, one word each
+//! globals           one word per Wasm global
+//! frames            call frames, one word per local, addressed from FRAME_POINTER,
+//!                   with room for a few frames of the largest function
+//! linear_memory     Wasm linear memory byte 0
+//! ```
+//!
+//! Every emitter that touches EVM memory asks a `Layout` for the offset
+//! instead of hard-coding it, so regions never overlap.
+use crate::info::ModuleInfo;
+use wain_ast::FuncKind;
+
+pub const WORD: u64 = 0x20;
+
+pub const SCRATCH: u64 = 0x00;
+pub const SCRATCH_SIZE: u64 = 2 * WORD;
+
+pub const RESERVED: u64 = SCRATCH + SCRATCH_SIZE;
+const RESERVED_SLOTS: u64 = 4;
+/// Current size of the linear memory in pages.
+pub const PAGES: u64 = RESERVED;
+/// Start of the frame of the running function.
+pub const FRAME_POINTER: u64 = RESERVED + WORD;
+
+/// Least room for the locals of the call frames.
+pub const FRAMES_SIZE: u64 = 0x8000;
+/// Frames of the largest function that fit in the frames region at once, so
+/// that modules with huge frames still get a few levels of calls.
+const NESTED_FRAMES: u64 = 4;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    pub globals: u64,
+    pub frames: u64,
+    pub linear_memory: u64,
+}
+
+impl Layout {
+    pub fn new(info: &ModuleInfo) -> Self {
+        let globals = RESERVED + RESERVED_SLOTS * WORD;
+        let frames = globals + info.module.globals.len() as u64 * WORD;
+        let frames_size = FRAMES_SIZE.max(largest_frame(info) * NESTED_FRAMES);
+        let linear_memory = frames + frames_size;
+
+        Layout {
+            globals,
+            frames,
+            linear_memory,
+        }
+    }
+
+    pub fn frames_end(&self) -> u64 {
+        self.linear_memory
+    }
+
+    pub fn global(&self, idx: u32) -> u64 {
+        self.globals + idx as u64 * WORD
+    }
+}
+
+/// Offset of a local from the frame pointer.
+pub fn local(idx: u32) -> u64 {
+    idx as u64 * WORD
+}
+
+/// Bytes of frame a function with `locals` params and declared locals takes.
+pub fn frame_size(locals: usize) -> u64 {
+    locals as u64 * WORD
+}
+
+/// Bytes of the largest frame among the functions of the module.
+fn largest_frame(info: &ModuleInfo) -> u64 {
+    info.funcs()
+        .map(|(idx, func)| {
+            let params = info.func_type(idx).params.len();
+            match &func.kind {
+                FuncKind::Body { locals, .. } => frame_size(params + locals.len()),
+                FuncKind::Import(_) => frame_size(params),
+            }
+        })
+        .max()
+        .unwrap_or(0)
+}
@@ -1,12 +1,18 @@
 extern crate wain_syntax_binary;
+mod abi;
+mod host;
+mod info;
+mod layout;
 mod revm_run;
 use ethabi::{encode, Token};
 use etk_asm::asm::Assembler;
 use etk_asm::ops::AbstractOp;
 use etk_asm::ops::Imm;
 use etk_asm::ops::Op;
+use host::Imports;
+use info::ModuleInfo;
+use layout::Layout;
 use primitive_types::U256;
-use rand::Rng;
 use revm::db::CacheDB;
 use revm::db::EmptyDB;
 use revm::InMemoryDB;
@@ -15,12 +21,72 @@ use std::collections::HashMap;
 use std::fs;
 use wain_ast::FuncKind;
 use wain_ast::*;
-use wain_exec::trap::Result;
+use wain_exec::trap::{Result, Trap, TrapReason};
 use wain_syntax_binary::parse;
 
+/// A block, loop or if that branches can target.
 #[derive(Debug)]
-pub struct Context {
-    labels: Vec<String>,
+pub struct LabelFrame {
+    name: String,
+    /// Operand stack height when the block was entered.
+    height: usize,
+    /// Number of values a branch to this label carries.
+    arity: usize,
+}
+
+#[derive(Debug)]
+pub struct Context<'a, 's> {
+    labels: Vec<LabelFrame>,
+    /// Operand stack height of the current function, not counting its return address.
+    height: usize,
+    layout: Layout,
+    max_pages: u32,
+    info: ModuleInfo<'a, 's>,
+    /// Bytes of frame the current function needs for its params and locals.
+    frame_size: u64,
+    /// Number of declared locals of the current function, which follow its params.
+    locals: usize,
+    /// Number of values the current function returns.
+    results: usize,
+    /// Index of the current function, keeps its labels apart from other functions'.
+    func: u32,
+    /// Labels handed out so far by `fresh_label`.
+    next_label: u32,
+    globals: &'a Globals,
+    imports: &'a Imports,
+}
+
+impl<'a, 's> Context<'a, 's> {
+    fn new(
+        info: ModuleInfo<'a, 's>,
+        globals: &'a Globals,
+        imports: &'a Imports,
+        idx: u32,
+        locals: &[ValType],
+    ) -> Self {
+        let ty = info.func_type(idx);
+        Context {
+            labels: Vec::new(),
+            height: 0,
+            layout: Layout::new(&info),
+            max_pages: memory_limits(info.module).1,
+            info,
+            frame_size: layout::frame_size(ty.params.len() + locals.len()),
+            locals: locals.len(),
+            results: ty.results.len(),
+            func: idx,
+            next_label: 0,
+            globals,
+            imports,
+        }
+    }
+
+    /// A label no other instruction in the contract uses.
+    fn fresh_label(&mut self) -> String {
+        let label = format!("L{}_{}", self.func, self.next_label);
+        self.next_label += 1;
+        label
+    }
 }
 
 pub enum Value {
@@ -30,9 +96,53 @@ pub enum Value {
     U64(u64),
 }
 
+/// How the exported functions of a module are deployed.
+pub enum Deployment {
+    /// One contract per exported function, called with the bare arguments.
+    /// Globals kept in storage are not shared between the exports.
+    PerFunction,
+    /// One contract for the whole module, dispatching on a selector in front of the arguments.
+    Module(abi::Selector),
+}
+
+/// Where the mutable globals of a module are kept.
+#[derive(Debug)]
+pub enum Globals {
+    /// In EVM memory, set to their initial value at the start of every call.
+    Memory,
+    /// The listed globals in contract storage, at `Globals::slot`, so they keep
+    /// their value across transactions. The rest stay in memory.
+    /// rustc places `__stack_pointer` at global 0.
+    ///
+    /// Storage belongs to a contract, so with `Deployment::PerFunction` every
+    /// export has its own copy of the stored globals and never sees what the
+    /// other exports write. Use `Deployment::Module` to share them.
+    Storage(Vec<u32>),
+}
+
+impl Globals {
+    fn stored(&self, idx: u32) -> bool {
+        match self {
+            Globals::Memory => false,
+            Globals::Storage(stored) => stored.contains(&idx),
+        }
+    }
+
+    /// Pushes the storage slot of global `idx`, `keccak256("wasm.globals") + idx`.
+    /// Hashing keeps the globals clear of the small keys contracts store under
+    /// with `env.sstore`, the way Solidity places its mappings.
+    fn slot(idx: u32) -> AbstractOp {
+        let base = U256::from_big_endian(revm_primitives::keccak256(b"wasm.globals").as_bytes());
+        let mut slot = [0u8; 32];
+        (base + U256::from(idx)).to_big_endian(&mut slot);
+        AbstractOp::Op(Op::Push32(Imm::from(slot)))
+    }
+}
+
 pub struct Runner<'module, 'source> {
     module: &'module Module<'source>,
     functions: HashMap<String, String>,
+    selectors: HashMap<String, u32>,
     db: CacheDB<EmptyDB>,
 }
 macro_rules! to_big_endian {
@@ -46,84 +156,189 @@ macro_rules! to_big_endian {
 
 impl<'m, 's> Runner<'m, 's> {
     pub fn instantiate(module: &'m Module<'s>) -> Result<Self> {
+        Self::instantiate_with(
+            module,
+            Deployment::PerFunction,
+            Globals::Memory,
+            Imports::default(),
+        )
+    }
+
+    pub fn instantiate_with(
+        module: &'m Module<'s>,
+        deployment: Deployment,
+        globals: Globals,
+        imports: Imports,
+    ) -> Result<Self> {
+        validate(module)?;
         let mut runtime = Self {
             module: module,
             functions: HashMap::new(),
+            selectors: HashMap::new(),
             db: InMemoryDB::new(EmptyDB::default()),
         };
 
-        fn find_func_name_by_id<'s>(id: u32, exports: &[Export<'s>]) -> Option<String> {
-            for export in exports {
-                match export.kind {
-                    wain_ast::ExportKind::Func(idx) => {
-                        if idx == id {
-                            return Some(export.name.0.to_string());
-                        }
-                    }
-                    _ => {}
-                };
-            }
-            None
+        let info = ModuleInfo::new(module);
+        imports.check(&info)?;
+        check_limits(&info)?;
+        let (data, segments) = data_segments(module)?;
+        let constructor = globals_storage_init(module, &globals);
+        let elements = table_elements(module)?;
+
+        let mut bodies: Vec<AbstractOp> = Vec::new();
+        for (idx, func) in info.funcs() {
+            let import_expr: Vec<Instruction>;
+            let (locals, expr): (&[ValType], &Vec<Instruction>) = match &func.kind {
+                FuncKind::Body { locals, expr } => (locals, expr),
+                // exports and tables jump to imports, so they get a body calling the binding
+                FuncKind::Import(_) => {
+                    import_expr = import_body(&info, idx);
+                    (&[], &import_expr)
+                }
+            };
+            let mut context = Context::new(info, &globals, &imports, idx, locals);
+            bodies.append(function_body(idx, expr, &mut context).as_mut());
         }
-        for funcs in &module.funcs {
-            let mut commands: Vec<AbstractOp> = Vec::new();
+        bodies.append(table(&info, &elements).as_mut());
 
-            let mut globals: Context = Context { labels: Vec::new() };
+        let mut exports: Vec<(String, u32)> = Vec::new();
+        for export in &module.exports {
+            if let ExportKind::Func(idx) = export.kind {
+                exports.push((export.name.0.to_string(), idx));
+            }
+        }
 
-            let length = module.types.get(funcs.idx as usize).unwrap().params.len();
-            commands.push(AbstractOp::Op(Op::Push2(Imm::from(
-                length as u16 * 0x20 as u16,
-            ))));
+        match deployment {
+            Deployment::PerFunction => {
+                for (name, idx) in exports {
+                    let mut commands: Vec<AbstractOp> = Vec::new();
+                    commands.append(init(&info, &globals, &segments).as_mut());
+                    commands.append(entry(&info, idx, 0).as_mut());
+                    commands.extend(bodies.iter().cloned());
 
-            commands.push(AbstractOp::Op(Op::Push1(Imm::from(0 as u8))));
-            commands.push(AbstractOp::Op(Op::Push1(Imm::from(0 as u8))));
+                    let address = runtime.deploy(&constructor, commands, &data)?;
+                    runtime.functions.insert(name, address);
+                }
+            }
+            Deployment::Module(scheme) => {
+                let mut selectors: Vec<(u32, u32)> = Vec::new();
+                let mut owners: HashMap<u32, (&str, u32)> = HashMap::new();
+                for (name, idx) in &exports {
+                    let selector = scheme.selector(name, *idx, info.func_type(*idx));
+                    // aliases of one function may share a selector, different functions may not
+                    if let Some((other, other_idx)) = owners.insert(selector, (name, *idx)) {
+                        if other_idx != *idx {
+                            return Err(Box::new(Trap {
+                                reason: TrapReason::ImportFuncCallFail {
+                                    mod_name: "abi".to_string(),
+                                    name: "selector".to_string(),
+                                    msg: format!(
+                                        "exports `{}` and `{}` share selector 0x{:08x}",
+                                        other, name, selector
+                                    ),
+                                },
+                                offset: 0,
+                            }));
+                        }
+                    }
+                    runtime.selectors.insert(name.clone(), selector);
+                    selectors.push((*idx, selector));
+                }
 
-            commands.push(AbstractOp::Op(Op::CallDataCopy));
+                // exports may alias a function, which still gets a single entry
+                let mut entries: Vec<u32> = exports.iter().map(|(_, idx)| *idx).collect();
+                entries.sort_unstable();
+                entries.dedup();
+
+                let mut commands: Vec<AbstractOp> = Vec::new();
+                commands.append(init(&info, &globals, &segments).as_mut());
+                commands.append(abi::dispatcher(&selectors).as_mut());
+                for idx in &entries {
+                    commands.push(AbstractOp::Label(abi::dispatch_label(*idx)));
+                    commands.push(AbstractOp::Op(Op::JumpDest));
+                    commands.push(AbstractOp::Op(Op::Pop));
+                    commands.append(entry(&info, *idx, abi::SELECTOR_SIZE).as_mut());
+                }
+                commands.extend(bodies.iter().cloned());
 
-            match &funcs.kind {
-                FuncKind::Import(s) => {}
-                FuncKind::Body { locals, expr } => {
-                    commands.append(instructions_handler(expr, &mut globals).as_mut());
+                let address = runtime.deploy(&constructor, commands, &data)?;
+                for (name, _) in exports {
+                    runtime.functions.insert(name, address.clone());
                 }
             }
-            commands.push(AbstractOp::Op(Op::Push1(Imm::from(0 as u8))));
-            commands.push(AbstractOp::Op(Op::MStore));
-            commands.push(AbstractOp::Op(Op::Push1(Imm::from(32 as u8))));
-            commands.push(AbstractOp::Op(Op::Push1(Imm::from(0 as u8))));
-            commands.push(AbstractOp::Op(Op::Return));
-            let mut asm = Assembler::new();
-
-            asm.push_all(commands).unwrap();
-            let mut output = asm.take();
-            asm.finish().unwrap();
-            let mut deployment: Vec<AbstractOp> = Vec::new();
-            deployment.push(AbstractOp::Op(Op::Push2(Imm::from(output.len() as u16))));
-            deployment.push(AbstractOp::Op(Op::Push1(Imm::from(15 as u8))));
-            deployment.push(AbstractOp::Op(Op::Push1(Imm::from(0 as u8))));
-            deployment.push(AbstractOp::Op(Op::CodeCopy));
-            deployment.push(AbstractOp::Op(Op::Push2(Imm::from(output.len() as u16))));
-            deployment.push(AbstractOp::Op(Op::Push1(Imm::from(0 as u8))));
-            deployment.push(AbstractOp::Op(Op::Return));
-            deployment.push(AbstractOp::Op(Op::Stop));
-            let mut asm2 = Assembler::new();
-            asm2.push_all(deployment).unwrap();
-            let mut output2 = asm2.take();
-            asm2.finish().unwrap();
-            assert!(15 == output2.len());
-            output2.append(&mut output);
-            let address = revm_run::deploy_contract(hex::encode(output2.clone()));
-
-            println!("result {:#?}", address.0);
-            runtime.functions.insert(
-                find_func_name_by_id(funcs.idx, &module.exports).unwrap(),
-                address.1,
-            );
-            runtime.db = address.2.unwrap();
         }
         Ok(runtime)
     }
-    pub fn invoke(&mut self, args: &[Value]) -> Option<ExecutionResult> {
+
+    /// Assembles `commands` followed by the trap handler and `data` into a
+    /// contract and deploys it, running `constructor` first. Returns the address,
+    /// or an error if the create does not succeed.
+    fn deploy(
+        &mut self,
+        constructor: &[AbstractOp],
+        mut commands: Vec<AbstractOp>,
+        data: &[u8],
+    ) -> Result<String> {
+        commands.push(AbstractOp::Label(TRAP_LABEL.to_string()));
+        commands.push(AbstractOp::Op(Op::JumpDest));
+        commands.push(AbstractOp::Op(Op::Invalid));
+        commands.append(panic(DIVIDE_BY_ZERO_LABEL, PANIC_DIVIDE_BY_ZERO).as_mut());
+        commands.append(panic(OVERFLOW_LABEL, PANIC_OVERFLOW).as_mut());
+        commands.push(AbstractOp::Label(DATA_LABEL.to_string()));
+        let mut asm = Assembler::new();
+
+        asm.push_all(commands).unwrap();
+        let mut output = asm.take();
+        asm.finish().unwrap();
+        output.extend_from_slice(data);
+        // also keeps the length within the PUSH2 operands below
+        if output.len() > MAX_CODE_SIZE {
+            return Err(Box::new(Trap {
+                reason: TrapReason::OutOfLimit {
+                    max: MAX_CODE_SIZE,
+                    idx: output.len(),
+                    kind: "contract code byte",
+                },
+                offset: 0,
+            }));
+        }
+        let mut deployment: Vec<AbstractOp> = constructor.to_vec();
+        deployment.push(AbstractOp::Op(Op::Push2(Imm::from(output.len() as u16))));
+        deployment.push(AbstractOp::Op(Op::Push2(Imm::with_label("runtime"))));
+        deployment.push(AbstractOp::Op(Op::Push1(Imm::from(0 as u8))));
+        deployment.push(AbstractOp::Op(Op::CodeCopy));
+        deployment.push(AbstractOp::Op(Op::Push2(Imm::from(output.len() as u16))));
+        deployment.push(AbstractOp::Op(Op::Push1(Imm::from(0 as u8))));
+        deployment.push(AbstractOp::Op(Op::Return));
+        deployment.push(AbstractOp::Label("runtime".to_string()));
+        let mut asm2 = Assembler::new();
+        asm2.push_all(deployment).unwrap();
+        let mut output2 = asm2.take();
+        asm2.finish().unwrap();
+        output2.append(&mut output);
+        let (result, address, db) =
+            revm_run::deploy_contract(hex::encode(output2.clone()), self.db.clone());
+        match address {
+            Some(address) => {
+                self.db = db.unwrap();
+                Ok(address)
+            }
+            None => Err(Box::new(Trap {
+                reason: TrapReason::ImportFuncCallFail {
+                    mod_name: "evm".to_string(),
+                    name: "create".to_string(),
+                    msg: format!("{:?}", result),
+                },
+                offset: 0,
+            })),
+        }
+    }
+
+    pub fn invoke(&mut self, name: &str, args: &[Value]) -> Option<ExecutionResult> {
         let mut arguments = String::new();
+        if let Some(selector) = self.selectors.get(name) {
+            arguments += &hex::encode(selector.to_be_bytes());
+        }
         for args in args {
             match args {
                 Value::U32(e) => {
@@ -134,25 +349,451 @@ impl<'m, 's> Runner<'m, 's> {
 
                 }
                 Value::I32(e) => {
-                    arguments += to_big_endian!(e);
-
+                    arguments += to_big_endian!(&(*e as u32));
                 }
                 Value::I64(e) => {
-                    arguments += to_big_endian!(e);
+                    arguments += to_big_endian!(&(*e as u64));
                 }
             };
         }
-        return Some(revm_run::call_contract(
-            self.functions.get("add").unwrap().clone(),
+        let (result, db) = revm_run::call_contract(
+            self.functions.get(name)?.clone(),
             arguments.to_string(),
             self.db.clone(),
-        ));
+        );
+        self.db = db.unwrap();
+        Some(result)
+    }
+
+    /// Like `invoke`, also decoding the logs the call emitted.
+    pub fn invoke_with_events(
+        &mut self,
+        name: &str,
+        args: &[Value],
+    ) -> Option<(ExecutionResult, Vec<revm_run::Event>)> {
+        let result = self.invoke(name, args)?;
+        let events = revm_run::events(&result);
+        Some((result, events))
     }
 }
 
+// i32 and i64 values live on the EVM stack zero-extended to 256 bits: an i32
+// is its 32-bit two's complement pattern with every bit above bit 31 clear,
+// an i64 likewise above bit 63. Emitters rely on their operands having this
+// form and leave their results in it, masking with BYTES4 or BYTES8 after
+// anything that can carry, borrow or sign-extend past the width. So equality
+// is plain EQ for both widths, while signed operations first sign-extend their
+// operands with `signed_operands`.
 const BYTES8: u64 = 0xFFFFFFFFFFFFFFFF;
 const BYTES4: u32 = 0xFFFFFFFF;
 
+/// log2 of the Wasm page size.
+const PAGE_BITS: u8 = 16;
+/// 4GiB of linear memory.
+const MAX_PAGES: u32 = 0x10000;
+/// Largest runtime code a contract can have, from EIP-170.
+const MAX_CODE_SIZE: usize = 0x6000;
+/// Out-of-bounds accesses jump here.
+const TRAP_LABEL: &str = "trap";
+/// Integer division or remainder by zero jumps here.
+const DIVIDE_BY_ZERO_LABEL: &str = "trap_divide_by_zero";
+/// Signed division of the smallest integer by -1 jumps here.
+const OVERFLOW_LABEL: &str = "trap_overflow";
+/// Selector of Solidity's `Panic(uint256)`, which arithmetic traps revert with
+/// so that callers and tooling can tell them apart.
+const PANIC_SELECTOR: u32 = 0x4e487b71;
+/// Panic code of an arithmetic overflow.
+const PANIC_OVERFLOW: u8 = 0x11;
+/// Panic code of a division or remainder by zero.
+const PANIC_DIVIDE_BY_ZERO: u8 = 0x12;
+/// Marks the end of the code, where the data segments are appended.
+const DATA_LABEL: &str = "data";
+/// Marks the function table, one `PUSH2 type PUSH2 func` pair per element.
+const TABLE_LABEL: &str = "table";
+/// Bytes of code taken by one table element.
+const TABLE_ENTRY_SIZE: u8 = 6;
+/// Type of an empty table element, matching no signature.
+const NULL_TYPE: u16 = 0xffff;
+
+/// An active data segment, stored `code_offset` bytes after `DATA_LABEL`.
+struct DataPlacement {
+    code_offset: u32,
+    memory_offset: u32,
+    len: u32,
+}
+
+/// Concatenates the data segments and records where each one goes in linear memory.
+fn data_segments(module: &Module) -> Result<(Vec<u8>, Vec<DataPlacement>)> {
+    let mut data: Vec<u8> = Vec::new();
+    let mut placements: Vec<DataPlacement> = Vec::new();
+    let buffer_size = (memory_limits(module).0 as usize) << PAGE_BITS;
+
+    for segment in &module.data {
+        let memory_offset = eval_const(module, &segment.offset)? as u32;
+        let segment_end = memory_offset as usize + segment.data.len();
+        if segment_end > buffer_size {
+            return Err(Box::new(Trap {
+                reason: TrapReason::DataSegmentOutOfBuffer {
+                    segment_end,
+                    buffer_size,
+                },
+                offset: segment.start,
+            }));
+        }
+        if segment.data.is_empty() {
+            continue;
+        }
+
+        placements.push(DataPlacement {
+            code_offset: data.len() as u32,
+            memory_offset,
+            len: segment.data.len() as u32,
+        });
+        data.extend_from_slice(&segment.data);
+    }
+
+    Ok((data, placements))
+}
+
+fn table_size(module: &Module) -> usize {
+    match module.tables.first() {
+        Some(table) => match table.ty.limit {
+            Limits::Range(min, _) | Limits::From(min) => min as usize,
+        },
+        None => 0,
+    }
+}
+
+/// Functions in the table once the element segments are applied.
+fn table_elements(module: &Module) -> Result<Vec<Option<u32>>> {
+    let table_size = table_size(module);
+    let mut elements: Vec<Option<u32>> = vec![None; table_size];
+
+    for segment in &module.elems {
+        let offset = eval_const(module, &segment.offset)? as u32 as usize;
+        let segment_end = offset + segment.init.len();
+        if segment_end > table_size {
+            return Err(Box::new(Trap {
+                reason: TrapReason::ElemSegmentLargerThanTable {
+                    segment_end,
+                    table_size,
+                },
+                offset: segment.start,
+            }));
+        }
+        for (i, funcidx) in segment.init.iter().enumerate() {
+            elements[offset + i] = Some(*funcidx);
+        }
+    }
+
+    Ok(elements)
+}
+
+/// Body of an imported function: forwards its params to the binding.
+fn import_body(info: &ModuleInfo, idx: u32) -> Vec<Instruction> {
+    let func = info.func(idx);
+    let params = info.func_type(idx).params.len();
+    let mut expr: Vec<Instruction> = Vec::new();
+
+    for i in 0..params {
+        expr.push(Instruction {
+            start: func.start,
+            kind: InsnKind::LocalGet(i as u32),
+        });
+    }
+    expr.push(Instruction {
+        start: func.start,
+        kind: InsnKind::Call(idx),
+    });
+
+    expr
+}
+
+/// The function table, placed after the bodies where it is never executed.
+fn table(info: &ModuleInfo, elements: &[Option<u32>]) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.push(AbstractOp::Label(TABLE_LABEL.to_string()));
+    for element in elements {
+        match element {
+            Some(idx) => {
+                let ty = info.canonical_type(info.func(*idx).idx);
+                result.push(AbstractOp::Op(Op::Push2(Imm::from(ty))));
+                result.push(AbstractOp::Op(Op::Push2(Imm::with_label(func_label(*idx)))));
+            }
+            None => {
+                result.push(AbstractOp::Op(Op::Push2(Imm::from(NULL_TYPE))));
+                result.push(AbstractOp::Op(Op::Push2(Imm::from(0 as u16))));
+            }
+        }
+    }
+
+    result
+}
+
+/// Value of a constant expression, as the bit pattern it has on the EVM stack.
+/// Imported globals have no value at compile time, so reading one is an error.
+fn eval_const(module: &Module, expr: &[Instruction]) -> Result<u64> {
+    match &expr[expr.len() - 1].kind {
+        InsnKind::I32Const(c) => Ok(*c as u32 as u64),
+        InsnKind::I64Const(c) => Ok(*c as u64),
+        InsnKind::GlobalGet(idx) => match &module.globals[*idx as usize].kind {
+            GlobalKind::Init(init) => eval_const(module, init),
+            GlobalKind::Import(import) => Err(Box::new(Trap {
+                reason: TrapReason::UnknownImport {
+                    mod_name: import.mod_name.0.to_string(),
+                    name: import.name.0.to_string(),
+                    kind: "global",
+                },
+                offset: expr[expr.len() - 1].start,
+            })),
+        },
+        _ => unreachable!("unexpected instruction in constant expression"),
+    }
+}
+
+/// Stands in for the bytes of a module that is validated after parsing,
+/// pointing validation errors at their offset.
+#[derive(Clone)]
+struct ModuleSource;
+
+impl wain_ast::source::Source for ModuleSource {
+    type Raw = ();
+
+    fn describe(&self, f: &mut std::fmt::Formatter<'_>, offset: usize) -> std::fmt::Result {
+        write!(f, " caused at byte offset {}", offset)
+    }
+
+    fn raw(&self) -> Self::Raw {}
+}
+
+/// Fails on modules that are not valid Wasm, whose blocks and branches the
+/// code generator would otherwise trust. `wain-validate` only knows single
+/// results, so modules using multiple results are compiled without it.
+fn validate(module: &Module) -> Result<()> {
+    if module.types.iter().any(|ty| ty.results.len() > 1) {
+        return Ok(());
+    }
+    let root = Root {
+        module: module.clone(),
+        source: ModuleSource,
+    };
+    wain_validate::validate(&root).map_err(|err| {
+        Box::new(Trap {
+            reason: TrapReason::ImportFuncCallFail {
+                mod_name: "wasm".to_string(),
+                name: "validate".to_string(),
+                msg: err.to_string(),
+            },
+            offset: err.offset(),
+        })
+    })
+}
+
+/// Most results a function can have, since returning rotates the return address
+/// up past all of them with a single `SWAPn`.
+const MAX_RESULTS: usize = 16;
+
+/// Largest frame a function can have, since frame sizes and local offsets are
+/// pushed as 4-byte immediates.
+const MAX_FRAME_SIZE: u64 = u32::MAX as u64;
+
+/// Most types a module can have, since type indices are pushed as 2-byte
+/// immediates in the function table and `NULL_TYPE` must stay unused.
+const MAX_TYPES: usize = NULL_TYPE as usize;
+
+/// Fails on the first signature or function that the code generator cannot
+/// lower within the EVM's limits, instead of emitting broken code.
+fn check_limits(info: &ModuleInfo) -> Result<()> {
+    if info.module.types.len() > MAX_TYPES {
+        return Err(Box::new(Trap {
+            reason: TrapReason::OutOfLimit {
+                max: MAX_TYPES,
+                idx: info.module.types.len(),
+                kind: "type",
+            },
+            offset: info.module.types[MAX_TYPES].start,
+        }));
+    }
+
+    for ty in &info.module.types {
+        if ty.results.len() > MAX_RESULTS {
+            return Err(Box::new(Trap {
+                reason: TrapReason::OutOfLimit {
+                    max: MAX_RESULTS,
+                    idx: ty.results.len(),
+                    kind: "function result",
+                },
+                offset: ty.start,
+            }));
+        }
+    }
+
+    for (idx, func) in info.funcs() {
+        if let FuncKind::Body { locals, .. } = &func.kind {
+            let count = info.func_type(idx).params.len() + locals.len();
+            if layout::frame_size(count) > MAX_FRAME_SIZE {
+                return Err(Box::new(Trap {
+                    reason: TrapReason::OutOfLimit {
+                        max: (MAX_FRAME_SIZE / layout::WORD) as usize,
+                        idx: count,
+                        kind: "local",
+                    },
+                    offset: func.start,
+                }));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Initial and maximum page count of the module's linear memory.
+fn memory_limits(module: &Module) -> (u32, u32) {
+    match module.memories.first() {
+        Some(memory) => match memory.ty.limit {
+            Limits::Range(min, max) => (min, max),
+            Limits::From(min) => (min, MAX_PAGES),
+        },
+        None => (0, 0),
+    }
+}
+
+fn func_label(idx: u32) -> String {
+    format!("func_{}", idx)
+}
+
+/// Initial value of a global. Imported globals are rejected by `Imports::check`
+/// before anything is compiled, so every global has one.
+fn global_init(module: &Module, global: &Global) -> u64 {
+    match &global.kind {
+        GlobalKind::Init(expr) => eval_const(module, expr)
+            .ok()
+            .expect("imports are checked before compiling"),
+        GlobalKind::Import(_) => unreachable!("imports are checked before compiling"),
+    }
+}
+
+/// Constructor code writing the initial values of the globals kept in storage.
+fn globals_storage_init(module: &Module, globals: &Globals) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    for (idx, global) in module.globals.iter().enumerate() {
+        let value = global_init(module, global);
+        if global.mutable && globals.stored(idx as u32) && value != 0 {
+            result.push(push(value));
+            result.push(Globals::slot(idx as u32));
+            result.push(AbstractOp::Op(Op::SStore));
+        }
+    }
+
+    result
+}
+
+/// Sets up the memory of a fresh contract call.
+fn init(info: &ModuleInfo, globals: &Globals, segments: &[DataPlacement]) -> Vec<AbstractOp> {
+    let module = info.module;
+    let mut result: Vec<AbstractOp> = Vec::new();
+    let layout = Layout::new(info);
+
+    result.push(AbstractOp::Op(Op::Push4(Imm::from(memory_limits(module).0))));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(layout::PAGES as u8))));
+    result.push(AbstractOp::Op(Op::MStore));
+    result.append(data_init(&layout, segments).as_mut());
+    for (idx, global) in module.globals.iter().enumerate() {
+        let value = global_init(module, global);
+        if global.mutable && !globals.stored(idx as u32) && value != 0 {
+            result.push(push(value));
+            result.push(push(layout.global(idx as u32)));
+            result.push(AbstractOp::Op(Op::MStore));
+        }
+    }
+    result.push(AbstractOp::Op(Op::Push8(Imm::from(layout.frames))));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(layout::FRAME_POINTER as u8))));
+    result.push(AbstractOp::Op(Op::MStore));
+
+    result
+}
+
+/// Contract entry for the exported function `idx`: pushes the calldata arguments
+/// found after `args_offset` and calls into the function body.
+fn entry(info: &ModuleInfo, idx: u32, args_offset: u32) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+    let ty = info.func_type(idx);
+    let return_label = format!("entry_{}", idx);
+
+    for (i, param) in ty.params.iter().enumerate() {
+        result.push(AbstractOp::Op(Op::Push4(Imm::from(
+            args_offset + i as u32 * 0x20,
+        ))));
+        result.push(AbstractOp::Op(Op::CallDataLoad));
+        // calldata words can be anything, so cut them to the canonical form
+        match param {
+            ValType::I32 => result.push(AbstractOp::Op(Op::Push4(Imm::from(BYTES4)))),
+            _ => result.push(AbstractOp::Op(Op::Push8(Imm::from(BYTES8)))),
+        }
+        result.push(AbstractOp::Op(Op::And));
+    }
+    result.push(AbstractOp::Op(Op::Push2(Imm::with_label(&return_label))));
+    result.push(AbstractOp::Op(Op::Push2(Imm::with_label(func_label(idx)))));
+    result.push(AbstractOp::Op(Op::Jump));
+
+    result.push(AbstractOp::Label(return_label));
+    result.push(AbstractOp::Op(Op::JumpDest));
+    result.append(epilogue(ty.results.len()).as_mut());
+
+    result
+}
+
+/// Code of a function, entered with `[args..., return address]` on the stack and
+/// leaving `[results...]` when it jumps back.
+fn function_body(idx: u32, body: &Vec<Instruction>, context: &mut Context) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+    let params = context.info.func_type(idx).params.len();
+
+    result.push(AbstractOp::Label(func_label(idx)));
+    result.push(AbstractOp::Op(Op::JumpDest));
+
+    // the frame must fit in the frames region
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(layout::FRAME_POINTER as u8))));
+    result.push(AbstractOp::Op(Op::MLoad));
+    result.push(AbstractOp::Op(Op::Push4(Imm::from(context.frame_size as u32))));
+    result.push(AbstractOp::Op(Op::Add));
+    result.push(AbstractOp::Op(Op::Push8(Imm::from(context.layout.frames_end()))));
+    result.push(AbstractOp::Op(Op::Lt));
+    result.push(AbstractOp::Op(Op::Push2(Imm::with_label(TRAP_LABEL))));
+    result.push(AbstractOp::Op(Op::JumpI));
+
+    for idx in (0..params).rev() {
+        result.push(AbstractOp::Op(Op::Swap1));
+        result.append(local_address(idx as u32).as_mut());
+        result.push(AbstractOp::Op(Op::MStore));
+    }
+
+    // frames are reused, so declared locals must be cleared
+    for idx in params..params + context.locals {
+        result.push(push(0));
+        result.append(local_address(idx as u32).as_mut());
+        result.push(AbstractOp::Op(Op::MStore));
+    }
+
+    // the body is a block whose label is the function's return
+    let return_label = format!("return_{}", idx);
+    context.labels.push(LabelFrame {
+        name: return_label.clone(),
+        height: 0,
+        arity: context.results,
+    });
+    result.append(instructions_handler(body, context).as_mut());
+    context.labels.pop();
+    result.push(AbstractOp::Label(return_label));
+    result.push(AbstractOp::Op(Op::JumpDest));
+    context.height = context.results;
+    result.append(return_fn(context).as_mut());
+
+    result
+}
+
 fn main() {
     let source = fs::read("add.wasm").unwrap();
     let mut commands: Vec<AbstractOp> = Vec::new();
@@ -167,9 +808,10 @@ fn main() {
             };
 
             println!("functions {:#?}", runtime.functions);
-            match runtime.invoke(&[Value::U32(8), Value::U64(1)]) {
-                Some(ret) => {
+            match runtime.invoke_with_events("add", &[Value::U32(8), Value::U64(1)]) {
+                Some((ret, events)) => {
                     println!("result = {:?}", ret);
+                    println!("events = {:#?}", events);
                 }
                 None => eprintln!("Execution was trapped"),
             }
@@ -184,22 +826,32 @@ fn instructions_handler(body: &Vec<Instruction>, context: &mut Context) -> Vec<A
     for instr in body {
         match &instr.kind {
             InsnKind::Block { ty, body } => {
-                let mut rng = rand::thread_rng();
-                let id: u32 = rng.gen();
-                context.labels.push(id.to_string());
+                let id = context.fresh_label();
+                context.labels.push(LabelFrame {
+                    name: id.clone(),
+                    height: context.height,
+                    arity: ty.is_some() as usize,
+                });
                 commands.append(instructions_handler(body, context).as_mut());
-                commands.push(AbstractOp::Label(id.to_string()));
+                commands.push(AbstractOp::Label(id.clone()));
                 commands.push(AbstractOp::Op(Op::JumpDest));
-                context.labels.pop();
+                let frame = context.labels.pop().unwrap();
+                context.height = frame.height + frame.arity;
             }
             InsnKind::Loop { ty, body } => {
-                let mut rng = rand::thread_rng();
-                let id: u32 = rng.gen();
-                context.labels.push(id.to_string());
-                commands.push(AbstractOp::Label(id.to_string()));
+                let id = context.fresh_label();
+                let height = context.height;
+                // branching to a loop restarts it, which takes no values
+                context.labels.push(LabelFrame {
+                    name: id.clone(),
+                    height,
+                    arity: 0,
+                });
+                commands.push(AbstractOp::Label(id.clone()));
                 commands.push(AbstractOp::Op(Op::JumpDest));
                 commands.append(instructions_handler(body, context).as_mut());
                 context.labels.pop();
+                context.height = height + ty.is_some() as usize;
             }
             InsnKind::I32Add => {
                 commands.append(i32Add().as_mut());
@@ -237,13 +889,13 @@ fn instructions_handler(body: &Vec<Instruction>, context: &mut Context) -> Vec<A
             InsnKind::I64Xor => {
                 commands.append(i64Xor().as_mut());
             }
-            InsnKind::I32Eq => {
+            InsnKind::I32Eq | InsnKind::I64Eq => {
                 commands.append(eq().as_mut());
             }
-            InsnKind::I32Eqz => {
+            InsnKind::I32Eqz | InsnKind::I64Eqz => {
                 commands.append(eqz().as_mut());
             }
-            InsnKind::I32Ne => {
+            InsnKind::I32Ne | InsnKind::I64Ne => {
                 commands.append(ne().as_mut());
             }
             InsnKind::I32LtS => {
@@ -371,6 +1023,7 @@ fn instructions_handler(body: &Vec<Instruction>, context: &mut Context) -> Vec<A
             }
             InsnKind::Unreachable => {
                 commands.append(unreachable().as_mut());
+                break;
             }
             InsnKind::LocalGet(idx) => {
                 commands.append(Local_get(idx).as_mut());
@@ -382,107 +1035,112 @@ fn instructions_handler(body: &Vec<Instruction>, context: &mut Context) -> Vec<A
                 commands.append(Local_tee(idx).as_mut());
             }
             InsnKind::BrIf(idx) => {
+                context.height -= 1;
                 commands.append(br_if(context, idx).as_mut());
             }
             InsnKind::Br(idx) => {
                 commands.append(br(context, idx).as_mut());
+                break;
             }
             InsnKind::Drop => {
                 commands.append(drop().as_mut());
             }
             InsnKind::Select => {
-                commands.append(select().as_mut());
+                commands.append(select(context).as_mut());
             }
             InsnKind::If {
                 ty,
                 then_body,
                 else_body,
             } => {
-                commands.append(if_fn().as_mut());
+                commands.append(if_fn(context, ty, then_body, else_body).as_mut());
             }
             InsnKind::Call(fnidx) => {
-                commands.append(call(fnidx).as_mut());
+                commands.append(call(context, fnidx).as_mut());
             }
             InsnKind::BrTable {
                 labels,
                 default_label,
             } => {
-                commands.append(br_table().as_mut());
+                context.height -= 1;
+                commands.append(br_table(context, labels, default_label).as_mut());
+                break;
             }
             InsnKind::Return => {
-                commands.append(return_fn().as_mut());
+                commands.append(return_fn(context).as_mut());
+                break;
             }
             InsnKind::CallIndirect(typidx) => {
-                commands.append(call_indirect(typidx).as_mut());
+                commands.append(call_indirect(context, typidx).as_mut());
             }
             InsnKind::GlobalGet(globalidx) => {
-                commands.append(global_get(globalidx).as_mut());
+                commands.append(global_get(context, globalidx).as_mut());
             }
             InsnKind::GlobalSet(globalidx) => {
-                commands.append(global_set(globalidx).as_mut());
+                commands.append(global_set(context, globalidx).as_mut());
             }
             InsnKind::I32Load8S(mem) => {
-                commands.append(i32_load_8s(mem).as_mut());
+                commands.append(i32_load_8s(context, mem).as_mut());
             }
             InsnKind::I32Load8U(mem) => {
-                commands.append(i32_load_8u(mem).as_mut());
+                commands.append(i32_load_8u(context, mem).as_mut());
             }
             InsnKind::I64Load8S(mem) => {
-                commands.append(i64_load_8s(mem).as_mut());
+                commands.append(i64_load_8s(context, mem).as_mut());
             }
             InsnKind::I64Load8U(mem) => {
-                commands.append(i64_load_8u(mem).as_mut());
+                commands.append(i64_load_8u(context, mem).as_mut());
             }
             InsnKind::I32Load16S(mem) => {
-                commands.append(i32_load_16s(mem).as_mut());
+                commands.append(i32_load_16s(context, mem).as_mut());
             }
             InsnKind::I32Load16U(mem) => {
-                commands.append(i32_load_16u(mem).as_mut());
+                commands.append(i32_load_16u(context, mem).as_mut());
             }
             InsnKind::I64Load16S(mem) => {
-                commands.append(i64_load_16s(mem).as_mut());
+                commands.append(i64_load_16s(context, mem).as_mut());
             }
             InsnKind::I64Load16U(mem) => {
-                commands.append(i64_load_16u(mem).as_mut());
+                commands.append(i64_load_16u(context, mem).as_mut());
             }
             InsnKind::I64Load32S(mem) => {
-                commands.append(i64_load_32s(mem).as_mut());
+                commands.append(i64_load_32s(context, mem).as_mut());
             }
             InsnKind::I64Load32U(mem) => {
-                commands.append(i64_load_32u(mem).as_mut());
+                commands.append(i64_load_32u(context, mem).as_mut());
             }
             InsnKind::I32Load(mem) => {
-                commands.append(i32_load(mem).as_mut());
+                commands.append(i32_load(context, mem).as_mut());
             }
             InsnKind::I64Load(mem) => {
-                commands.append(i64_load(mem).as_mut());
+                commands.append(i64_load(context, mem).as_mut());
             }
             InsnKind::I32Store(mem) => {
-                commands.append(i32_store(mem).as_mut());
+                commands.append(i32_store(context, mem).as_mut());
             }
             InsnKind::I64Store(mem) => {
-                commands.append(i64_store(mem).as_mut());
+                commands.append(i64_store(context, mem).as_mut());
             }
             InsnKind::I32Store8(mem) => {
-                commands.append(i32_store8(mem).as_mut());
+                commands.append(i32_store8(context, mem).as_mut());
             }
             InsnKind::I32Store16(mem) => {
-                commands.append(i32_store16(mem).as_mut());
+                commands.append(i32_store16(context, mem).as_mut());
             }
             InsnKind::I64Store8(mem) => {
-                commands.append(i64_store8(mem).as_mut());
+                commands.append(i64_store8(context, mem).as_mut());
             }
             InsnKind::I64Store16(mem) => {
-                commands.append(i64_store16(mem).as_mut());
+                commands.append(i64_store16(context, mem).as_mut());
             }
             InsnKind::I64Store32(mem) => {
-                commands.append(i64_store32(mem).as_mut());
+                commands.append(i64_store32(context, mem).as_mut());
             }
             InsnKind::MemorySize => {
                 commands.append(memory_size().as_mut());
             }
             InsnKind::MemoryGrow => {
-                commands.append(memory_grow().as_mut());
+                commands.append(memory_grow(context).as_mut());
             }
             InsnKind::I32Const(c) => {
                 commands.append(i32_const_fn(c).as_mut());
@@ -503,15 +1161,162 @@ fn instructions_handler(body: &Vec<Instruction>, context: &mut Context) -> Vec<A
                 commands.push(AbstractOp::Op(Op::Invalid));
             }
         };
+
+        let (pops, pushes) = stack_effect(context, &instr.kind);
+        context.height = context.height - pops + pushes;
     }
 
     commands
 }
 
-fn if_fn() -> Vec<AbstractOp> {
+/// Values an instruction pops and pushes. Control instructions keep
+/// `Context::height` up to date themselves and report no effect.
+fn stack_effect(context: &Context, kind: &InsnKind) -> (usize, usize) {
+    match kind {
+        InsnKind::Block { .. }
+        | InsnKind::Loop { .. }
+        | InsnKind::If { .. }
+        | InsnKind::Br(_)
+        | InsnKind::BrIf(_)
+        | InsnKind::BrTable { .. }
+        | InsnKind::Return
+        | InsnKind::Unreachable
+        | InsnKind::Nop => (0, 0),
+        InsnKind::Call(fnidx) => {
+            let ty = context.info.func_type(*fnidx);
+            (ty.params.len(), ty.results.len())
+        }
+        InsnKind::CallIndirect(typidx) => {
+            let ty = context.info.ty(*typidx);
+            (ty.params.len() + 1, ty.results.len())
+        }
+        InsnKind::Drop | InsnKind::LocalSet(_) | InsnKind::GlobalSet(_) => (1, 0),
+        InsnKind::Select => (3, 1),
+        InsnKind::LocalGet(_)
+        | InsnKind::GlobalGet(_)
+        | InsnKind::MemorySize
+        | InsnKind::I32Const(_)
+        | InsnKind::I64Const(_)
+        | InsnKind::F32Const(_)
+        | InsnKind::F64Const(_) => (0, 1),
+        InsnKind::I32Store(_)
+        | InsnKind::I64Store(_)
+        | InsnKind::F32Store(_)
+        | InsnKind::F64Store(_)
+        | InsnKind::I32Store8(_)
+        | InsnKind::I32Store16(_)
+        | InsnKind::I64Store8(_)
+        | InsnKind::I64Store16(_)
+        | InsnKind::I64Store32(_) => (2, 0),
+        InsnKind::LocalTee(_)
+        | InsnKind::MemoryGrow
+        | InsnKind::I32Load(_)
+        | InsnKind::I64Load(_)
+        | InsnKind::F32Load(_)
+        | InsnKind::F64Load(_)
+        | InsnKind::I32Load8S(_)
+        | InsnKind::I32Load8U(_)
+        | InsnKind::I32Load16S(_)
+        | InsnKind::I32Load16U(_)
+        | InsnKind::I64Load8S(_)
+        | InsnKind::I64Load8U(_)
+        | InsnKind::I64Load16S(_)
+        | InsnKind::I64Load16U(_)
+        | InsnKind::I64Load32S(_)
+        | InsnKind::I64Load32U(_)
+        | InsnKind::I32Clz
+        | InsnKind::I32Ctz
+        | InsnKind::I32Popcnt
+        | InsnKind::I64Clz
+        | InsnKind::I64Ctz
+        | InsnKind::I64Popcnt
+        | InsnKind::F32Abs
+        | InsnKind::F32Neg
+        | InsnKind::F32Ceil
+        | InsnKind::F32Floor
+        | InsnKind::F32Trunc
+        | InsnKind::F32Nearest
+        | InsnKind::F32Sqrt
+        | InsnKind::F64Abs
+        | InsnKind::F64Neg
+        | InsnKind::F64Ceil
+        | InsnKind::F64Floor
+        | InsnKind::F64Trunc
+        | InsnKind::F64Nearest
+        | InsnKind::F64Sqrt
+        | InsnKind::I32Eqz
+        | InsnKind::I64Eqz
+        | InsnKind::I32WrapI64
+        | InsnKind::I32TruncF32S
+        | InsnKind::I32TruncF32U
+        | InsnKind::I32TruncF64S
+        | InsnKind::I32TruncF64U
+        | InsnKind::I64ExtendI32S
+        | InsnKind::I64ExtendI32U
+        | InsnKind::I64TruncF32S
+        | InsnKind::I64TruncF32U
+        | InsnKind::I64TruncF64S
+        | InsnKind::I64TruncF64U
+        | InsnKind::F32ConvertI32S
+        | InsnKind::F32ConvertI32U
+        | InsnKind::F32ConvertI64S
+        | InsnKind::F32ConvertI64U
+        | InsnKind::F32DemoteF64
+        | InsnKind::F64ConvertI32S
+        | InsnKind::F64ConvertI32U
+        | InsnKind::F64ConvertI64S
+        | InsnKind::F64ConvertI64U
+        | InsnKind::F64PromoteF32
+        | InsnKind::I32ReinterpretF32
+        | InsnKind::I64ReinterpretF64
+        | InsnKind::F32ReinterpretI32
+        | InsnKind::F64ReinterpretI64 => (1, 1),
+        // binary arithmetic and comparisons
+        _ => (2, 1),
+    }
+}
+
+/// Lowers `if` to a conditional jump over the then branch. Branches inside
+/// either arm target the join point after the else branch.
+fn if_fn(
+    context: &mut Context,
+    ty: &Option<ValType>,
+    then_body: &Vec<Instruction>,
+    else_body: &Vec<Instruction>,
+) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
+    let end = context.fresh_label();
+    let else_label = context.fresh_label();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    result.push(AbstractOp::Op(Op::IsZero));
+    if else_body.is_empty() {
+        result.push(AbstractOp::Op(Op::Push2(Imm::with_label(&end))));
+    } else {
+        result.push(AbstractOp::Op(Op::Push2(Imm::with_label(&else_label))));
+    }
+    result.push(AbstractOp::Op(Op::JumpI));
+
+    context.height -= 1;
+    let height = context.height;
+    context.labels.push(LabelFrame {
+        name: end.clone(),
+        height,
+        arity: ty.is_some() as usize,
+    });
+    result.append(instructions_handler(then_body, context).as_mut());
+    if !else_body.is_empty() {
+        result.push(AbstractOp::Op(Op::Push2(Imm::with_label(&end))));
+        result.push(AbstractOp::Op(Op::Jump));
+        result.push(AbstractOp::Label(else_label.clone()));
+        result.push(AbstractOp::Op(Op::JumpDest));
+        context.height = height;
+        result.append(instructions_handler(else_body, context).as_mut());
+    }
+    let frame = context.labels.pop().unwrap();
+    context.height = frame.height + frame.arity;
+
+    result.push(AbstractOp::Label(end.clone()));
+    result.push(AbstractOp::Op(Op::JumpDest));
 
     result
 }
@@ -527,7 +1332,8 @@ fn i64_wrap_i32u() -> Vec<AbstractOp> {
 fn i32_wrap_i64() -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    result.push(AbstractOp::Op(Op::Push4(Imm::from(BYTES4))));
+    result.push(AbstractOp::Op(Op::And));
 
     result
 }
@@ -535,9 +1341,7 @@ fn i32_wrap_i64() -> Vec<AbstractOp> {
 fn i64_extend_i32s() -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Push4(Imm::from(BYTES4))));
-    result.push(AbstractOp::Op(Op::And));
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(0 as u8))));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(3 as u8))));
     result.push(AbstractOp::Op(Op::SignExtend));
     result.push(AbstractOp::Op(Op::Push8(Imm::from(BYTES8))));
     result.push(AbstractOp::Op(Op::And));
@@ -545,15 +1349,9 @@ fn i64_extend_i32s() -> Vec<AbstractOp> {
     result
 }
 
+/// A canonical i32 already is the zero-extended i64.
 fn i64_extend_i32u() -> Vec<AbstractOp> {
-    let mut result: Vec<AbstractOp> = Vec::new();
-
-    result.push(AbstractOp::Op(Op::Push4(Imm::from(BYTES4))));
-    result.push(AbstractOp::Op(Op::And));
-    result.push(AbstractOp::Op(Op::Push8(Imm::from(BYTES8))));
-    result.push(AbstractOp::Op(Op::And));
-
-    result
+    Vec::new()
 }
 
 fn i64_wrap_i32s() -> Vec<AbstractOp> {
@@ -564,202 +1362,635 @@ fn i64_wrap_i32s() -> Vec<AbstractOp> {
     result
 }
 
-fn i32_const_fn(c: &i32) -> Vec<AbstractOp> {
-    let mut result: Vec<AbstractOp> = Vec::new();
+/// Pushes `value` with the smallest `PUSHn` that holds it.
+fn push(value: u64) -> AbstractOp {
+    let bytes = value.to_be_bytes();
+    let start = (value.leading_zeros() / 8).min(7) as usize;
+    let imm = &bytes[start..];
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    let op = match imm.len() {
+        1 => Op::Push1(Imm::from([imm[0]])),
+        2 => Op::Push2(Imm::from([imm[0], imm[1]])),
+        3 => Op::Push3(Imm::from([imm[0], imm[1], imm[2]])),
+        4 => Op::Push4(Imm::from([imm[0], imm[1], imm[2], imm[3]])),
+        5 => Op::Push5(Imm::from([imm[0], imm[1], imm[2], imm[3], imm[4]])),
+        6 => Op::Push6(Imm::from([imm[0], imm[1], imm[2], imm[3], imm[4], imm[5]])),
+        7 => Op::Push7(Imm::from([
+            imm[0], imm[1], imm[2], imm[3], imm[4], imm[5], imm[6],
+        ])),
+        _ => Op::Push8(Imm::from(bytes)),
+    };
 
-    result
+    AbstractOp::Op(op)
 }
 
-fn i64_const_fn(c: &i64) -> Vec<AbstractOp> {
+/// Negative constants are pushed as their 32-bit two's complement, like the
+/// results of the masked i32 arithmetic.
+fn i32_const_fn(c: &i32) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    result.push(push(*c as u32 as u64));
 
     result
 }
 
-fn call(fnidx: &u32) -> Vec<AbstractOp> {
+/// Negative constants are pushed as their 64-bit two's complement.
+fn i64_const_fn(c: &i64) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Push4(Imm::from(*fnidx as u32))));
-    result.push(AbstractOp::Op(Op::Call));
+    result.push(push(*c as u64));
 
     result
 }
 
-fn memory_size() -> Vec<AbstractOp> {
-    let mut result: Vec<AbstractOp> = Vec::new();
-
-    result.push(AbstractOp::Op(Op::Invalid));
+/// Jumps into the callee with `[args..., return address]`, moving the frame
+/// pointer past the caller's frame for the duration of the call.
+fn call(context: &mut Context, fnidx: &u32) -> Vec<AbstractOp> {
+    if let FuncKind::Import(import) = &context.info.func(*fnidx).kind {
+        // imported functions are replaced by the code of their binding
+        let host = context
+            .imports
+            .get(&import.mod_name.0, &import.name.0)
+            .expect("imports are checked before compiling");
+        return (host.emit)(context);
+    }
 
-    result
+    enter(context, Some(func_label(*fnidx)))
 }
 
-fn memory_grow() -> Vec<AbstractOp> {
+/// Jumps to `callee`, or to the code offset on top of `[args..., target]` if
+/// there is none, and restores the frame pointer once it returns.
+fn enter(context: &mut Context, callee: Option<String>) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Invalid));
-
-    result
-}
-
-fn br_table() -> Vec<AbstractOp> {
-    let mut result: Vec<AbstractOp> = Vec::new();
+    let return_label = context.fresh_label();
+    result.push(AbstractOp::Op(Op::Push2(Imm::with_label(&return_label))));
+    if callee.is_none() {
+        result.push(AbstractOp::Op(Op::Swap1));
+    }
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(layout::FRAME_POINTER as u8))));
+    result.push(AbstractOp::Op(Op::MLoad));
+    result.push(AbstractOp::Op(Op::Push4(Imm::from(context.frame_size as u32))));
+    result.push(AbstractOp::Op(Op::Add));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(layout::FRAME_POINTER as u8))));
+    result.push(AbstractOp::Op(Op::MStore));
 
-    result
-}
+    if let Some(callee) = callee {
+        result.push(AbstractOp::Op(Op::Push2(Imm::with_label(callee))));
+    }
+    result.push(AbstractOp::Op(Op::Jump));
 
-fn return_fn() -> Vec<AbstractOp> {
-    let mut result: Vec<AbstractOp> = Vec::new();
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(0 as u8))));
+    result.push(AbstractOp::Label(return_label));
+    result.push(AbstractOp::Op(Op::JumpDest));
+    result.push(AbstractOp::Op(Op::Push4(Imm::from(context.frame_size as u32))));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(layout::FRAME_POINTER as u8))));
+    result.push(AbstractOp::Op(Op::MLoad));
+    result.push(AbstractOp::Op(Op::Sub));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(layout::FRAME_POINTER as u8))));
     result.push(AbstractOp::Op(Op::MStore));
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(32 as u8))));
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(0 as u8))));
-    result.push(AbstractOp::Op(Op::Return));
+
     result
 }
 
-fn call_indirect(typidx: &u32) -> Vec<AbstractOp> {
+/// Copies the data segments from the code into linear memory.
+fn data_init(layout: &Layout, segments: &[DataPlacement]) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    for segment in segments {
+        result.push(AbstractOp::Op(Op::Push4(Imm::from(segment.len))));
+        result.push(AbstractOp::Op(Op::Push2(Imm::with_label(DATA_LABEL))));
+        if segment.code_offset > 0 {
+            result.push(AbstractOp::Op(Op::Push4(Imm::from(segment.code_offset))));
+            result.push(AbstractOp::Op(Op::Add));
+        }
+        result.push(AbstractOp::Op(Op::Push8(Imm::from(
+            layout.linear_memory + segment.memory_offset as u64,
+        ))));
+        result.push(AbstractOp::Op(Op::CodeCopy));
+    }
 
     result
 }
 
-fn global_get(globalIdx: &u32) -> Vec<AbstractOp> {
+fn memory_size() -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(layout::PAGES as u8))));
+    result.push(AbstractOp::Op(Op::MLoad));
 
     result
 }
 
-fn global_set(globalIdx: &u32) -> Vec<AbstractOp> {
+/// Only bumps the page counter; EVM memory is zeroed and gets paid for on first touch.
+fn memory_grow(context: &mut Context) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(layout::PAGES as u8))));
+    result.push(AbstractOp::Op(Op::MLoad));
+    result.push(AbstractOp::Op(Op::Swap1));
+    result.push(AbstractOp::Op(Op::Dup2));
+    result.push(AbstractOp::Op(Op::Add));
 
-    result
-}
+    let failed = context.fresh_label();
+    result.push(AbstractOp::Op(Op::Dup1));
+    result.push(AbstractOp::Op(Op::Push4(Imm::from(context.max_pages))));
+    result.push(AbstractOp::Op(Op::Lt));
+    result.push(AbstractOp::Op(Op::Push2(Imm::with_label(&failed))));
+    result.push(AbstractOp::Op(Op::JumpI));
 
-fn i32_load_8s(mem: &Mem) -> Vec<AbstractOp> {
-    let mut result: Vec<AbstractOp> = Vec::new();
+    let exit = context.fresh_label();
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(layout::PAGES as u8))));
+    result.push(AbstractOp::Op(Op::MStore));
+    result.push(AbstractOp::Op(Op::Push2(Imm::with_label(&exit))));
+    result.push(AbstractOp::Op(Op::Jump));
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    result.push(AbstractOp::Label(failed.clone()));
+    result.push(AbstractOp::Op(Op::JumpDest));
+    result.push(AbstractOp::Op(Op::Pop));
+    result.push(AbstractOp::Op(Op::Pop));
+    result.push(AbstractOp::Op(Op::Push4(Imm::from(BYTES4))));
+
+    result.push(AbstractOp::Label(exit.clone()));
+    result.push(AbstractOp::Op(Op::JumpDest));
 
     result
 }
 
-fn i32_load_8u(mem: &Mem) -> Vec<AbstractOp> {
-    let mut result: Vec<AbstractOp> = Vec::new();
+/// Tables up to this size are lowered to a chain of comparisons.
+const BR_TABLE_CHAIN_MAX: usize = 4;
 
-    result.push(AbstractOp::Op(Op::Invalid));
+/// Label that branch depth `idx` refers to, counting outwards from the innermost block.
+fn label<'c>(context: &'c Context, idx: &u32) -> &'c LabelFrame {
+    &context.labels[context.labels.len() - 1 - *idx as usize]
+}
 
-    result
+/// Number of values between the ones a branch to `target` carries and the
+/// stack the target block was entered with.
+fn excess(context: &Context, target: &LabelFrame) -> usize {
+    debug_assert!(
+        context.height >= target.height + target.arity,
+        "branch carrying {} values from a stack of {} to a block entered at {}",
+        target.arity,
+        context.height,
+        target.height
+    );
+    context.height - target.height - target.arity
 }
 
-fn i32_load_16s(mem: &Mem) -> Vec<AbstractOp> {
+/// Drops `drop` values from under the top `keep` values.
+fn unwind(keep: usize, drop: usize) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    for _ in 0..drop {
+        // rotates the value under the kept ones to the top
+        for depth in 1..=keep {
+            result.push(AbstractOp::Op(swap(depth)));
+        }
+        result.push(AbstractOp::Op(Op::Pop));
+    }
 
     result
 }
 
-fn i32_load_16u(mem: &Mem) -> Vec<AbstractOp> {
+/// Unwinds the stack to the target block and jumps to it.
+fn branch(context: &Context, idx: &u32) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
+    let target = label(context, idx);
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    result.append(unwind(target.arity, excess(context, target)).as_mut());
+    result.push(AbstractOp::Op(Op::Push2(Imm::with_label(&target.name))));
+    result.push(AbstractOp::Op(Op::Jump));
 
     result
 }
 
-fn i64_load_8s(mem: &Mem) -> Vec<AbstractOp> {
+fn br_table(context: &mut Context, labels: &Vec<u32>, default_label: &u32) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
+    let default_case = context.fresh_label();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    if labels.len() <= BR_TABLE_CHAIN_MAX {
+        let cases: Vec<String> = labels.iter().map(|_| context.fresh_label()).collect();
+        for (k, case) in cases.iter().enumerate() {
+            result.push(AbstractOp::Op(Op::Dup1));
+            result.push(AbstractOp::Op(Op::Push4(Imm::from(k as u32))));
+            result.push(AbstractOp::Op(Op::Eq));
+            result.push(AbstractOp::Op(Op::Push2(Imm::with_label(case))));
+            result.push(AbstractOp::Op(Op::JumpI));
+        }
+        result.push(AbstractOp::Op(Op::Push2(Imm::with_label(&default_case))));
+        result.push(AbstractOp::Op(Op::Jump));
+
+        for (case, idx) in cases.iter().zip(labels) {
+            result.push(AbstractOp::Label(case.clone()));
+            result.push(AbstractOp::Op(Op::JumpDest));
+            result.push(AbstractOp::Op(Op::Pop));
+            result.append(branch(context, idx).as_mut());
+        }
+    } else {
+        // the table is a run of PUSH2 <target>, the targets are read back with CODECOPY
+        let table = context.fresh_label();
+        result.push(AbstractOp::Op(Op::Push4(Imm::from(labels.len() as u32))));
+        result.push(AbstractOp::Op(Op::Dup2));
+        result.push(AbstractOp::Op(Op::Lt));
+        result.push(AbstractOp::Op(Op::IsZero));
+        result.push(AbstractOp::Op(Op::Push2(Imm::with_label(&default_case))));
+        result.push(AbstractOp::Op(Op::JumpI));
+
+        result.push(AbstractOp::Op(Op::Push1(Imm::from(3 as u8))));
+        result.push(AbstractOp::Op(Op::Mul));
+        result.push(AbstractOp::Op(Op::Push2(Imm::with_label(&table))));
+        result.push(AbstractOp::Op(Op::Add));
+        result.push(AbstractOp::Op(Op::Push1(Imm::from(1 as u8))));
+        result.push(AbstractOp::Op(Op::Add));
+        result.push(AbstractOp::Op(Op::Push1(Imm::from(2 as u8))));
+        result.push(AbstractOp::Op(Op::Swap1));
+        result.push(AbstractOp::Op(Op::Push1(Imm::from(layout::SCRATCH as u8))));
+        result.push(AbstractOp::Op(Op::CodeCopy));
+        result.push(AbstractOp::Op(Op::Push1(Imm::from(layout::SCRATCH as u8))));
+        result.push(AbstractOp::Op(Op::MLoad));
+        result.push(AbstractOp::Op(Op::Push1(Imm::from(0xf0 as u8))));
+        result.push(AbstractOp::Op(Op::Shr));
+        result.push(AbstractOp::Op(Op::Jump));
+
+        // targets that need values dropped go through a landing pad doing it
+        let mut landings: Vec<AbstractOp> = Vec::new();
+        result.push(AbstractOp::Label(table.clone()));
+        for idx in labels {
+            let target = label(context, idx);
+            if excess(context, target) == 0 {
+                result.push(AbstractOp::Op(Op::Push2(Imm::with_label(&target.name))));
+            } else {
+                let landing = context.fresh_label();
+                result.push(AbstractOp::Op(Op::Push2(Imm::with_label(&landing))));
+                landings.push(AbstractOp::Label(landing.clone()));
+                landings.push(AbstractOp::Op(Op::JumpDest));
+                landings.append(branch(context, idx).as_mut());
+            }
+        }
+        result.append(landings.as_mut());
+    }
+
+    result.push(AbstractOp::Label(default_case.clone()));
+    result.push(AbstractOp::Op(Op::JumpDest));
+    result.push(AbstractOp::Op(Op::Pop));
+    result.append(branch(context, default_label).as_mut());
 
     result
 }
 
-fn i64_load_8u(mem: &Mem) -> Vec<AbstractOp> {
+/// Drops everything but the results, moves the return address from under
+/// them to the top and jumps back.
+fn return_fn(context: &Context) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    debug_assert!(context.height >= context.results);
+    result.append(unwind(context.results, context.height - context.results).as_mut());
+    for depth in 1..=context.results {
+        result.push(AbstractOp::Op(swap(depth)));
+    }
+    result.push(AbstractOp::Op(Op::Jump));
 
     result
 }
-fn i32_load(mem: &Mem) -> Vec<AbstractOp> {
+
+fn swap(depth: usize) -> Op<etk_asm::ops::Abstract> {
+    match depth {
+        1 => Op::Swap1,
+        2 => Op::Swap2,
+        3 => Op::Swap3,
+        4 => Op::Swap4,
+        5 => Op::Swap5,
+        6 => Op::Swap6,
+        7 => Op::Swap7,
+        8 => Op::Swap8,
+        9 => Op::Swap9,
+        10 => Op::Swap10,
+        11 => Op::Swap11,
+        12 => Op::Swap12,
+        13 => Op::Swap13,
+        14 => Op::Swap14,
+        15 => Op::Swap15,
+        16 => Op::Swap16,
+        _ => unreachable!("stack access deeper than 16, see `check_limits`"),
+    }
+}
+
+/// Returns `[results...]` from the contract, one word each with the first
+/// result first. Results past the scratch space overwrite whatever follows
+/// it, which is fine since the call ends here.
+fn epilogue(results: usize) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
-    result.push(AbstractOp::Op(Op::Invalid));
+    for i in (0..results as u64).rev() {
+        result.push(push(layout::SCRATCH + i * layout::WORD));
+        result.push(AbstractOp::Op(Op::MStore));
+    }
+    result.push(push(results as u64 * layout::WORD));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(layout::SCRATCH as u8))));
+    result.push(AbstractOp::Op(Op::Return));
+    result
+}
+
+/// Looks the element up in the table and calls it, trapping when the index is
+/// out of the table, the element is empty or its signature is not `typidx`.
+fn call_indirect(context: &mut Context, typidx: &u32) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    // [args..., i]
+    result.push(push(table_size(context.info.module) as u64));
+    result.push(AbstractOp::Op(Op::Dup2));
+    result.push(AbstractOp::Op(Op::Lt));
+    result.push(AbstractOp::Op(Op::IsZero));
+    result.push(AbstractOp::Op(Op::Push2(Imm::with_label(TRAP_LABEL))));
+    result.push(AbstractOp::Op(Op::JumpI));
+
+    // copy `PUSH2 type PUSH2 func` of the element into scratch
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(TABLE_ENTRY_SIZE))));
+    result.push(AbstractOp::Op(Op::Mul));
+    result.push(AbstractOp::Op(Op::Push2(Imm::with_label(TABLE_LABEL))));
+    result.push(AbstractOp::Op(Op::Add));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(TABLE_ENTRY_SIZE))));
+    result.push(AbstractOp::Op(Op::Swap1));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(layout::SCRATCH as u8))));
+    result.push(AbstractOp::Op(Op::CodeCopy));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(layout::SCRATCH as u8))));
+    result.push(AbstractOp::Op(Op::MLoad));
+
+    // bytes 1..3 hold the type
+    result.push(AbstractOp::Op(Op::Dup1));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(0xe8 as u8))));
+    result.push(AbstractOp::Op(Op::Shr));
+    result.push(AbstractOp::Op(Op::Push2(Imm::from(0xffff as u16))));
+    result.push(AbstractOp::Op(Op::And));
+    result.push(AbstractOp::Op(Op::Push2(Imm::from(context.info.canonical_type(*typidx)))));
+    result.push(AbstractOp::Op(Op::Eq));
+    result.push(AbstractOp::Op(Op::IsZero));
+    result.push(AbstractOp::Op(Op::Push2(Imm::with_label(TRAP_LABEL))));
+    result.push(AbstractOp::Op(Op::JumpI));
+
+    // bytes 4..6 hold the function
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(0xd0 as u8))));
+    result.push(AbstractOp::Op(Op::Shr));
+    result.push(AbstractOp::Op(Op::Push2(Imm::from(0xffff as u16))));
+    result.push(AbstractOp::Op(Op::And));
+
+    result.append(enter(context, None).as_mut());
 
     result
 }
 
-fn i64_load(mem: &Mem) -> Vec<AbstractOp> {
+/// Immutable globals are pushed as constants, the others are read from the
+/// slot or memory word they live in.
+fn global_get(context: &Context, idx: &u32) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
-    result.push(AbstractOp::Op(Op::Invalid));
+    let global = &context.info.module.globals[*idx as usize];
+
+    if !global.mutable {
+        result.push(push(global_init(context.info.module, global)));
+    } else if context.globals.stored(*idx) {
+        result.push(Globals::slot(*idx));
+        result.push(AbstractOp::Op(Op::SLoad));
+        // storage is shared with the host functions, so keep the value canonical
+        match global.ty {
+            ValType::I32 => result.push(AbstractOp::Op(Op::Push4(Imm::from(BYTES4)))),
+            _ => result.push(AbstractOp::Op(Op::Push8(Imm::from(BYTES8)))),
+        }
+        result.push(AbstractOp::Op(Op::And));
+    } else {
+        result.push(push(context.layout.global(*idx)));
+        result.push(AbstractOp::Op(Op::MLoad));
+    }
 
     result
 }
 
-fn i32_store(mem: &Mem) -> Vec<AbstractOp> {
+fn global_set(context: &Context, idx: &u32) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    if context.globals.stored(*idx) {
+        result.push(Globals::slot(*idx));
+        result.push(AbstractOp::Op(Op::SStore));
+    } else {
+        result.push(push(context.layout.global(*idx)));
+        result.push(AbstractOp::Op(Op::MStore));
+    }
 
     result
 }
 
-fn i32_store8(mem: &Mem) -> Vec<AbstractOp> {
+/// Checks that a `bytes` wide access at `[addr]` + `mem.offset` stays inside
+/// the linear memory, jumping to the trap handler otherwise. Leaves `[addr]`.
+fn memory_bounds_check(mem: &Mem, bytes: u8) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    result.push(AbstractOp::Op(Op::Dup1));
+    result.push(AbstractOp::Op(Op::Push8(Imm::from(
+        mem.offset as u64 + bytes as u64,
+    ))));
+    result.push(AbstractOp::Op(Op::Add));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(layout::PAGES as u8))));
+    result.push(AbstractOp::Op(Op::MLoad));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(PAGE_BITS))));
+    result.push(AbstractOp::Op(Op::Shl));
+    result.push(AbstractOp::Op(Op::Lt));
+    result.push(AbstractOp::Op(Op::Push2(Imm::with_label(TRAP_LABEL))));
+    result.push(AbstractOp::Op(Op::JumpI));
 
     result
 }
 
-fn i32_store16(mem: &Mem) -> Vec<AbstractOp> {
+/// Turns the Wasm address `[addr]` into the EVM memory offset of `addr + mem.offset`.
+fn memory_address(context: &Context, mem: &Mem) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    result.push(AbstractOp::Op(Op::Push8(Imm::from(
+        context.layout.linear_memory + mem.offset as u64,
+    ))));
+    result.push(AbstractOp::Op(Op::Add));
 
     result
 }
 
-fn i64_store(mem: &Mem) -> Vec<AbstractOp> {
+/// Loads `bytes` little-endian bytes from `[addr]` and zero-extends them.
+fn load(context: &Context, mem: &Mem, bytes: u8) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    result.append(memory_bounds_check(mem, bytes).as_mut());
+    result.append(memory_address(context, mem).as_mut());
+    result.push(AbstractOp::Op(Op::MLoad));
+
+    // MLOAD is big-endian, so byte i of the word has weight 256^i in Wasm
+    result.push(AbstractOp::Op(Op::Dup1));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(0 as u8))));
+    result.push(AbstractOp::Op(Op::Byte));
+    for i in 1..bytes {
+        result.push(AbstractOp::Op(Op::Dup2));
+        result.push(AbstractOp::Op(Op::Push1(Imm::from(i))));
+        result.push(AbstractOp::Op(Op::Byte));
+        result.push(AbstractOp::Op(Op::Push1(Imm::from(i * 8))));
+        result.push(AbstractOp::Op(Op::Shl));
+        result.push(AbstractOp::Op(Op::Or));
+    }
+    result.push(AbstractOp::Op(Op::Swap1));
+    result.push(AbstractOp::Op(Op::Pop));
 
     result
 }
 
-fn i64_store8(mem: &Mem) -> Vec<AbstractOp> {
+/// Stores the low `bytes` bytes of `[addr, value]` little-endian at `addr`.
+fn store(context: &Context, mem: &Mem, bytes: u8) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    result.push(AbstractOp::Op(Op::Swap1));
+    result.append(memory_bounds_check(mem, bytes).as_mut());
+    result.append(memory_address(context, mem).as_mut());
+
+    for i in 0..bytes {
+        result.push(AbstractOp::Op(Op::Dup2));
+        if i > 0 {
+            result.push(AbstractOp::Op(Op::Push1(Imm::from(i * 8))));
+            result.push(AbstractOp::Op(Op::Shr));
+        }
+        result.push(AbstractOp::Op(Op::Dup2));
+        if i > 0 {
+            result.push(AbstractOp::Op(Op::Push1(Imm::from(i))));
+            result.push(AbstractOp::Op(Op::Add));
+        }
+        result.push(AbstractOp::Op(Op::MStore8));
+    }
+    result.push(AbstractOp::Op(Op::Pop));
+    result.push(AbstractOp::Op(Op::Pop));
 
     result
 }
 
-fn i64_store16(mem: &Mem) -> Vec<AbstractOp> {
+fn i32_load_8s(context: &Context, mem: &Mem) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    result.append(load(context, mem, 1).as_mut());
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(0 as u8))));
+    result.push(AbstractOp::Op(Op::SignExtend));
+    result.push(AbstractOp::Op(Op::Push4(Imm::from(BYTES4))));
+    result.push(AbstractOp::Op(Op::And));
 
     result
 }
 
-fn i64_store32(mem: &Mem) -> Vec<AbstractOp> {
+fn i32_load_8u(context: &Context, mem: &Mem) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    result.append(load(context, mem, 1).as_mut());
+
+    result
+}
+
+fn i32_load_16s(context: &Context, mem: &Mem) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.append(load(context, mem, 2).as_mut());
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(1 as u8))));
+    result.push(AbstractOp::Op(Op::SignExtend));
+    result.push(AbstractOp::Op(Op::Push4(Imm::from(BYTES4))));
+    result.push(AbstractOp::Op(Op::And));
+
+    result
+}
+
+fn i32_load_16u(context: &Context, mem: &Mem) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.append(load(context, mem, 2).as_mut());
+
+    result
+}
+
+fn i64_load_8s(context: &Context, mem: &Mem) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.append(load(context, mem, 1).as_mut());
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(0 as u8))));
+    result.push(AbstractOp::Op(Op::SignExtend));
+    result.push(AbstractOp::Op(Op::Push8(Imm::from(BYTES8))));
+    result.push(AbstractOp::Op(Op::And));
+
+    result
+}
+
+fn i64_load_8u(context: &Context, mem: &Mem) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.append(load(context, mem, 1).as_mut());
+
+    result
+}
+fn i32_load(context: &Context, mem: &Mem) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.append(load(context, mem, 4).as_mut());
+
+    result
+}
+
+fn i64_load(context: &Context, mem: &Mem) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.append(load(context, mem, 8).as_mut());
+
+    result
+}
+
+fn i32_store(context: &Context, mem: &Mem) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.append(store(context, mem, 4).as_mut());
+
+    result
+}
+
+fn i32_store8(context: &Context, mem: &Mem) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.append(store(context, mem, 1).as_mut());
+
+    result
+}
+
+fn i32_store16(context: &Context, mem: &Mem) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.append(store(context, mem, 2).as_mut());
+
+    result
+}
+
+fn i64_store(context: &Context, mem: &Mem) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.append(store(context, mem, 8).as_mut());
+
+    result
+}
+
+fn i64_store8(context: &Context, mem: &Mem) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.append(store(context, mem, 1).as_mut());
+
+    result
+}
+
+fn i64_store16(context: &Context, mem: &Mem) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.append(store(context, mem, 2).as_mut());
+
+    result
+}
+
+fn i64_store32(context: &Context, mem: &Mem) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.append(store(context, mem, 4).as_mut());
 
     result
 }
@@ -786,34 +2017,42 @@ fn i64Add() -> Vec<AbstractOp> {
     result
 }
 
-fn i64_load_16s(mem: &Mem) -> Vec<AbstractOp> {
+fn i64_load_16s(context: &Context, mem: &Mem) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    result.append(load(context, mem, 2).as_mut());
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(1 as u8))));
+    result.push(AbstractOp::Op(Op::SignExtend));
+    result.push(AbstractOp::Op(Op::Push8(Imm::from(BYTES8))));
+    result.push(AbstractOp::Op(Op::And));
 
     result
 }
 
-fn i64_load_16u(mem: &Mem) -> Vec<AbstractOp> {
+fn i64_load_16u(context: &Context, mem: &Mem) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    result.append(load(context, mem, 2).as_mut());
 
     result
 }
 
-fn i64_load_32s(mem: &Mem) -> Vec<AbstractOp> {
+fn i64_load_32s(context: &Context, mem: &Mem) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    result.append(load(context, mem, 4).as_mut());
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(3 as u8))));
+    result.push(AbstractOp::Op(Op::SignExtend));
+    result.push(AbstractOp::Op(Op::Push8(Imm::from(BYTES8))));
+    result.push(AbstractOp::Op(Op::And));
 
     result
 }
 
-fn i64_load_32u(mem: &Mem) -> Vec<AbstractOp> {
+fn i64_load_32u(context: &Context, mem: &Mem) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    result.append(load(context, mem, 4).as_mut());
 
     result
 }
@@ -921,8 +2160,8 @@ fn i64Xor() -> Vec<AbstractOp> {
     result
 }
 
+/// Equal values have equal canonical forms, whatever their width.
 fn eq() -> Vec<AbstractOp> {
-    //TODO might be wrong
     let mut result: Vec<AbstractOp> = Vec::new();
 
     result.push(AbstractOp::Op(Op::Eq));
@@ -939,12 +2178,10 @@ fn eqz() -> Vec<AbstractOp> {
 }
 
 fn ne() -> Vec<AbstractOp> {
-    //TODO might be wrong
     let mut result: Vec<AbstractOp> = Vec::new();
 
     result.push(AbstractOp::Op(Op::Eq));
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(0x1 as u8))));
-    result.push(AbstractOp::Op(Op::Xor));
+    result.push(AbstractOp::Op(Op::IsZero));
 
     result
 }
@@ -975,209 +2212,210 @@ fn extend16s() -> Vec<AbstractOp> {
     result
 }
 
-fn i32Lts() -> Vec<AbstractOp> {
+/// Sign-extends both operands of `[a, b]` from `bytes` wide values, leaving
+/// `[b, a]` with the first operand on top as EVM operations expect it.
+fn signed_operands(bytes: u8) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(3 as u8))));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(bytes - 1))));
     result.push(AbstractOp::Op(Op::SignExtend));
     result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(3 as u8))));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(bytes - 1))));
     result.push(AbstractOp::Op(Op::SignExtend));
-    result.push(AbstractOp::Op(Op::SLt));
 
     result
 }
 
-fn i64Lts() -> Vec<AbstractOp> {
+/// Masks a sign-extended result back to `bytes` wide.
+fn unsigned_result(bytes: u8) -> AbstractOp {
+    match bytes {
+        4 => AbstractOp::Op(Op::Push4(Imm::from(BYTES4))),
+        _ => AbstractOp::Op(Op::Push8(Imm::from(BYTES8))),
+    }
+}
+
+fn lt_s(bytes: u8) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(7 as u8))));
-    result.push(AbstractOp::Op(Op::SignExtend));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(7 as u8))));
-    result.push(AbstractOp::Op(Op::SignExtend));
+    result.append(signed_operands(bytes).as_mut());
     result.push(AbstractOp::Op(Op::SLt));
 
     result
 }
 
-fn i32Gts() -> Vec<AbstractOp> {
+fn gt_s(bytes: u8) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(3 as u8))));
-    result.push(AbstractOp::Op(Op::SignExtend));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(3 as u8))));
-    result.push(AbstractOp::Op(Op::SignExtend));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::SLt));
+    result.append(signed_operands(bytes).as_mut());
+    result.push(AbstractOp::Op(Op::SGt));
 
     result
 }
 
-fn i64Gts() -> Vec<AbstractOp> {
+fn le_s(bytes: u8) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(7 as u8))));
-    result.push(AbstractOp::Op(Op::SignExtend));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(7 as u8))));
-    result.push(AbstractOp::Op(Op::SignExtend));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::SLt));
+    result.append(signed_operands(bytes).as_mut());
+    result.push(AbstractOp::Op(Op::SGt));
+    result.push(AbstractOp::Op(Op::IsZero));
 
     result
 }
 
-fn Leu() -> Vec<AbstractOp> {
+fn ge_s(bytes: u8) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Dup2));
-    result.push(AbstractOp::Op(Op::Dup2));
-    result.push(AbstractOp::Op(Op::Eq));
-    result.push(AbstractOp::Op(Op::Swap2));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Lt));
-    result.push(AbstractOp::Op(Op::Or));
+    result.append(signed_operands(bytes).as_mut());
+    result.push(AbstractOp::Op(Op::SLt));
+    result.push(AbstractOp::Op(Op::IsZero));
 
     result
 }
-fn Geu() -> Vec<AbstractOp> {
+
+/// Signed division traps on a zero divisor, and on the smallest integer
+/// divided by -1 whose quotient does not fit the width.
+fn div_s(bytes: u8) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Dup2));
-    result.push(AbstractOp::Op(Op::Dup2));
+    result.append(divisor_check().as_mut());
+    result.push(AbstractOp::Op(Op::Dup1));
+    result.push(unsigned_result(bytes));
     result.push(AbstractOp::Op(Op::Eq));
-    result.push(AbstractOp::Op(Op::Swap2));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Gt));
-    result.push(AbstractOp::Op(Op::Or));
+    result.push(AbstractOp::Op(Op::Dup3));
+    result.push(push(1 << (bytes * 8 - 1)));
+    result.push(AbstractOp::Op(Op::Eq));
+    result.push(AbstractOp::Op(Op::And));
+    result.push(AbstractOp::Op(Op::Push2(Imm::with_label(OVERFLOW_LABEL))));
+    result.push(AbstractOp::Op(Op::JumpI));
+    result.append(signed_operands(bytes).as_mut());
+    result.push(AbstractOp::Op(Op::SDiv));
+    result.push(unsigned_result(bytes));
+    result.push(AbstractOp::Op(Op::And));
 
     result
 }
 
-fn i32Ges() -> Vec<AbstractOp> {
+/// SMOD takes the sign of the dividend like Wasm does, and the smallest
+/// integer modulo -1 is 0 rather than an overflow.
+fn rem_s(bytes: u8) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(3 as u8))));
-    result.push(AbstractOp::Op(Op::SignExtend));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(3 as u8))));
-    result.push(AbstractOp::Op(Op::SignExtend));
-    result.push(AbstractOp::Op(Op::Dup2));
-    result.push(AbstractOp::Op(Op::Dup2));
-    result.push(AbstractOp::Op(Op::Eq));
-    result.push(AbstractOp::Op(Op::Swap2));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::SGt));
-    result.push(AbstractOp::Op(Op::Or));
+    result.append(divisor_check().as_mut());
+    result.append(signed_operands(bytes).as_mut());
+    result.push(AbstractOp::Op(Op::SMod));
+    result.push(unsigned_result(bytes));
+    result.push(AbstractOp::Op(Op::And));
 
     result
 }
 
-fn i64Ges() -> Vec<AbstractOp> {
-    let mut result: Vec<AbstractOp> = Vec::new();
+fn i32Lts() -> Vec<AbstractOp> {
+    lt_s(4)
+}
 
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(7 as u8))));
-    result.push(AbstractOp::Op(Op::SignExtend));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(7 as u8))));
-    result.push(AbstractOp::Op(Op::SignExtend));
-    result.push(AbstractOp::Op(Op::Dup2));
-    result.push(AbstractOp::Op(Op::Dup2));
-    result.push(AbstractOp::Op(Op::Eq));
-    result.push(AbstractOp::Op(Op::Swap2));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::SGt));
-    result.push(AbstractOp::Op(Op::Or));
+fn i64Lts() -> Vec<AbstractOp> {
+    lt_s(8)
+}
 
-    result
+fn i32Gts() -> Vec<AbstractOp> {
+    gt_s(4)
 }
 
-fn i32Les() -> Vec<AbstractOp> {
+fn i64Gts() -> Vec<AbstractOp> {
+    gt_s(8)
+}
+
+fn Leu() -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(3 as u8))));
-    result.push(AbstractOp::Op(Op::SignExtend));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(3 as u8))));
-    result.push(AbstractOp::Op(Op::SignExtend));
-    result.push(AbstractOp::Op(Op::Dup2));
-    result.push(AbstractOp::Op(Op::Dup2));
-    result.push(AbstractOp::Op(Op::Dup2));
-    result.push(AbstractOp::Op(Op::Eq));
-    result.push(AbstractOp::Op(Op::Swap2));
     result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::SLt));
-    result.push(AbstractOp::Op(Op::Or));
+    result.push(AbstractOp::Op(Op::Gt));
+    result.push(AbstractOp::Op(Op::IsZero));
 
     result
 }
-
-fn i64Les() -> Vec<AbstractOp> {
+fn Geu() -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(7 as u8))));
-    result.push(AbstractOp::Op(Op::SignExtend));
     result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(7 as u8))));
-    result.push(AbstractOp::Op(Op::SignExtend));
-    result.push(AbstractOp::Op(Op::Dup2));
-    result.push(AbstractOp::Op(Op::Dup2));
-    result.push(AbstractOp::Op(Op::Dup2));
-    result.push(AbstractOp::Op(Op::Eq));
-    result.push(AbstractOp::Op(Op::Swap2));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::SLt));
-    result.push(AbstractOp::Op(Op::Or));
+    result.push(AbstractOp::Op(Op::Lt));
+    result.push(AbstractOp::Op(Op::IsZero));
 
     result
 }
 
-fn Divu() -> Vec<AbstractOp> {
+fn i32Ges() -> Vec<AbstractOp> {
+    ge_s(4)
+}
+
+fn i64Ges() -> Vec<AbstractOp> {
+    ge_s(8)
+}
+
+fn i32Les() -> Vec<AbstractOp> {
+    le_s(4)
+}
+
+fn i64Les() -> Vec<AbstractOp> {
+    le_s(8)
+}
+
+/// Handler at `label` reverting with `Panic(code)`.
+fn panic(label: &str, code: u8) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Div));
+    result.push(AbstractOp::Label(label.to_string()));
+    result.push(AbstractOp::Op(Op::JumpDest));
+    result.push(AbstractOp::Op(Op::Push4(Imm::from(PANIC_SELECTOR))));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(0xe0 as u8))));
+    result.push(AbstractOp::Op(Op::Shl));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(layout::SCRATCH as u8))));
+    result.push(AbstractOp::Op(Op::MStore));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(code))));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(layout::SCRATCH as u8 + 4))));
+    result.push(AbstractOp::Op(Op::MStore));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(0x24 as u8))));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(layout::SCRATCH as u8))));
+    result.push(AbstractOp::Op(Op::Revert));
 
     result
 }
 
-fn i32Divs() -> Vec<AbstractOp> {
+/// Traps when the divisor on top of `[a, b]` is zero, where EVM division
+/// would quietly give zero.
+fn divisor_check() -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(3 as u8))));
-    result.push(AbstractOp::Op(Op::SignExtend));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(3 as u8))));
-    result.push(AbstractOp::Op(Op::SignExtend));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::SDiv));
+    result.push(AbstractOp::Op(Op::Dup1));
+    result.push(AbstractOp::Op(Op::IsZero));
+    result.push(AbstractOp::Op(Op::Push2(Imm::with_label(DIVIDE_BY_ZERO_LABEL))));
+    result.push(AbstractOp::Op(Op::JumpI));
 
     result
 }
 
-fn i64Divs() -> Vec<AbstractOp> {
+fn Divu() -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(7 as u8))));
-    result.push(AbstractOp::Op(Op::SignExtend));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(7 as u8))));
-    result.push(AbstractOp::Op(Op::SignExtend));
-    result.push(AbstractOp::Op(Op::Swap1));
+    result.append(divisor_check().as_mut());
     result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::SDiv));
+    result.push(AbstractOp::Op(Op::Div));
 
     result
 }
 
+fn i32Divs() -> Vec<AbstractOp> {
+    div_s(4)
+}
+
+fn i64Divs() -> Vec<AbstractOp> {
+    div_s(8)
+}
+
 fn Remu() -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
+    result.append(divisor_check().as_mut());
     result.push(AbstractOp::Op(Op::Swap1));
     result.push(AbstractOp::Op(Op::Mod));
 
@@ -1185,33 +2423,11 @@ fn Remu() -> Vec<AbstractOp> {
 }
 
 fn i32Rems() -> Vec<AbstractOp> {
-    let mut result: Vec<AbstractOp> = Vec::new();
-
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(3 as u8))));
-    result.push(AbstractOp::Op(Op::SignExtend));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(3 as u8))));
-    result.push(AbstractOp::Op(Op::SignExtend));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::SMod));
-
-    result
+    rem_s(4)
 }
 
 fn i64Rems() -> Vec<AbstractOp> {
-    let mut result: Vec<AbstractOp> = Vec::new();
-
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(7 as u8))));
-    result.push(AbstractOp::Op(Op::SignExtend));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(7 as u8))));
-    result.push(AbstractOp::Op(Op::SignExtend));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::SMod));
-
-    result
+    rem_s(8)
 }
 
 fn i32Gtu() -> Vec<AbstractOp> {
@@ -1253,9 +2469,8 @@ fn i64Ltu() -> Vec<AbstractOp> {
 fn i32Shrs() -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(20 as u8))));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Mod));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(31 as u8))));
+    result.push(AbstractOp::Op(Op::And));
     result.push(AbstractOp::Op(Op::Swap1));
     result.push(AbstractOp::Op(Op::Push1(Imm::from(3 as u8))));
     result.push(AbstractOp::Op(Op::SignExtend));
@@ -1270,9 +2485,8 @@ fn i32Shrs() -> Vec<AbstractOp> {
 fn i64Shrs() -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(40 as u8))));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Mod));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(63 as u8))));
+    result.push(AbstractOp::Op(Op::And));
     result.push(AbstractOp::Op(Op::Swap1));
     result.push(AbstractOp::Op(Op::Push1(Imm::from(7 as u8))));
     result.push(AbstractOp::Op(Op::SignExtend));
@@ -1284,92 +2498,180 @@ fn i64Shrs() -> Vec<AbstractOp> {
     result
 }
 
-fn i32Rotl() -> Vec<AbstractOp> {
+/// Rotates `[x, k]` by `k mod bits`. The bits shifted out past the width come
+/// back in from the other side via the opposite shift by `bits - k`, which is
+/// a no-op for `k = 0` since canonical values have no bits above the width.
+fn rotate(bits: u8, left: bool) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
+    let (first, second) = if left { (Op::Shl, Op::Shr) } else { (Op::Shr, Op::Shl) };
 
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(bits - 1))));
+    result.push(AbstractOp::Op(Op::And));
+    result.push(AbstractOp::Op(Op::Dup2));
+    result.push(AbstractOp::Op(Op::Dup2));
+    result.push(AbstractOp::Op(first));
+    result.push(AbstractOp::Op(Op::Swap2));
     result.push(AbstractOp::Op(Op::Swap1));
-    //TODO PrepareCall
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(bits))));
+    result.push(AbstractOp::Op(Op::Sub));
+    result.push(AbstractOp::Op(second));
+    result.push(AbstractOp::Op(Op::Or));
+    result.push(unsigned_result(bits / 8));
+    result.push(AbstractOp::Op(Op::And));
+
     result
 }
 
-fn i64Rotl() -> Vec<AbstractOp> {
-    let mut result: Vec<AbstractOp> = Vec::new();
+fn i32Rotl() -> Vec<AbstractOp> {
+    rotate(32, true)
+}
 
-    result.push(AbstractOp::Op(Op::Swap1));
-    //TODO PrepareCall
-    result
+fn i64Rotl() -> Vec<AbstractOp> {
+    rotate(64, true)
 }
 
 fn i32Rotr() -> Vec<AbstractOp> {
-    let mut result: Vec<AbstractOp> = Vec::new();
-
-    result.push(AbstractOp::Op(Op::Swap1));
-    //TODO PrepareCall
-    result
+    rotate(32, false)
 }
 
 fn i64Rotr() -> Vec<AbstractOp> {
-    let mut result: Vec<AbstractOp> = Vec::new();
+    rotate(64, false)
+}
 
-    result.push(AbstractOp::Op(Op::Swap1));
-    //TODO PrepareCall
-    result
+/// `byte` repeated over the low `bits` bits, e.g. `0x55555555` for 32 bits.
+fn repeated(byte: u8, bits: u8) -> u64 {
+    (u64::MAX / 0xff * byte as u64) >> (64 - bits)
 }
 
-fn i32Popcnt() -> Vec<AbstractOp> {
+/// SWAR population count of the `bits`-wide value on top of the stack: bits
+/// are summed pairwise, then per nibble and per byte, and a multiplication by
+/// `0x0101..` gathers the byte sums into the top byte of the width.
+fn popcount(bits: u8) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    // x - ((x >> 1) & 0x55..)
+    result.push(AbstractOp::Op(Op::Dup1));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(1u8))));
+    result.push(AbstractOp::Op(Op::Shr));
+    result.push(push(repeated(0x55, bits)));
+    result.push(AbstractOp::Op(Op::And));
+    result.push(AbstractOp::Op(Op::Swap1));
+    result.push(AbstractOp::Op(Op::Sub));
 
-    result
-}
+    // (x & 0x33..) + ((x >> 2) & 0x33..)
+    result.push(AbstractOp::Op(Op::Dup1));
+    result.push(push(repeated(0x33, bits)));
+    result.push(AbstractOp::Op(Op::And));
+    result.push(AbstractOp::Op(Op::Swap1));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(2u8))));
+    result.push(AbstractOp::Op(Op::Shr));
+    result.push(push(repeated(0x33, bits)));
+    result.push(AbstractOp::Op(Op::And));
+    result.push(AbstractOp::Op(Op::Add));
 
-fn i64Popcnt() -> Vec<AbstractOp> {
-    let mut result: Vec<AbstractOp> = Vec::new();
+    // (x + (x >> 4)) & 0x0f..
+    result.push(AbstractOp::Op(Op::Dup1));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(4u8))));
+    result.push(AbstractOp::Op(Op::Shr));
+    result.push(AbstractOp::Op(Op::Add));
+    result.push(push(repeated(0x0f, bits)));
+    result.push(AbstractOp::Op(Op::And));
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    // ((x * 0x01..) >> (bits - 8)) & 0xff
+    result.push(push(repeated(0x01, bits)));
+    result.push(AbstractOp::Op(Op::Mul));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(bits - 8))));
+    result.push(AbstractOp::Op(Op::Shr));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(0xffu8))));
+    result.push(AbstractOp::Op(Op::And));
 
     result
 }
 
-fn i32Ctz() -> Vec<AbstractOp> {
+/// Counts the leading zeros of the `bits`-wide value on top of the stack by
+/// binary search, without branches: at each step the value is shifted up by
+/// `step` bits when its top `step` bits are clear, and the count grows by the
+/// same amount. Only a zero value is still zero at the end, and gets one more.
+fn leading_zeros(bits: u8) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(0u8))));
+    result.push(AbstractOp::Op(Op::Swap1));
+
+    let mut step = bits / 2;
+    while step > 0 {
+        // [n, x] -> [n, x, (x < 2^(bits - step)) * step]
+        result.push(push(1 << (bits - step)));
+        result.push(AbstractOp::Op(Op::Dup2));
+        result.push(AbstractOp::Op(Op::Lt));
+        result.push(AbstractOp::Op(Op::Push1(Imm::from(step))));
+        result.push(AbstractOp::Op(Op::Mul));
+        // -> [n + t, x << t]
+        result.push(AbstractOp::Op(Op::Dup1));
+        result.push(AbstractOp::Op(Op::Swap2));
+        result.push(AbstractOp::Op(Op::Swap1));
+        result.push(AbstractOp::Op(Op::Shl));
+        result.push(AbstractOp::Op(Op::Swap2));
+        result.push(AbstractOp::Op(Op::Add));
+        result.push(AbstractOp::Op(Op::Swap1));
+        step /= 2;
+    }
+
+    result.push(AbstractOp::Op(Op::IsZero));
+    result.push(AbstractOp::Op(Op::Add));
 
     result
 }
 
-fn i64Ctz() -> Vec<AbstractOp> {
+/// Counts the trailing zeros as the population count of the bits below the
+/// lowest set one: `x & -x` isolates that bit and subtracting one sets every
+/// bit under it. Zero wraps around to all ones, which the mask cuts to `bits`.
+fn trailing_zeros(bits: u8) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Invalid));
+    result.push(AbstractOp::Op(Op::Dup1));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(0u8))));
+    result.push(AbstractOp::Op(Op::Sub));
+    result.push(AbstractOp::Op(Op::And));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(1u8))));
+    result.push(AbstractOp::Op(Op::Swap1));
+    result.push(AbstractOp::Op(Op::Sub));
+    result.push(unsigned_result(bits / 8));
+    result.push(AbstractOp::Op(Op::And));
+    result.append(popcount(bits).as_mut());
 
     result
 }
 
-fn i32Clz() -> Vec<AbstractOp> {
-    let mut result: Vec<AbstractOp> = Vec::new();
+fn i32Popcnt() -> Vec<AbstractOp> {
+    popcount(32)
+}
 
-    result.push(AbstractOp::Op(Op::Invalid));
+fn i64Popcnt() -> Vec<AbstractOp> {
+    popcount(64)
+}
 
-    result
+fn i32Ctz() -> Vec<AbstractOp> {
+    trailing_zeros(32)
 }
 
-fn i64Clz() -> Vec<AbstractOp> {
-    let mut result: Vec<AbstractOp> = Vec::new();
+fn i64Ctz() -> Vec<AbstractOp> {
+    trailing_zeros(64)
+}
 
-    result.push(AbstractOp::Op(Op::Invalid));
+fn i32Clz() -> Vec<AbstractOp> {
+    leading_zeros(32)
+}
 
-    result
+fn i64Clz() -> Vec<AbstractOp> {
+    leading_zeros(64)
 }
 
 fn i32Shru() -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(20 as u8))));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Mod));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(31 as u8))));
+    result.push(AbstractOp::Op(Op::And));
     result.push(AbstractOp::Op(Op::Shr));
     result.push(AbstractOp::Op(Op::Push4(Imm::from(BYTES4))));
     result.push(AbstractOp::Op(Op::And));
@@ -1380,9 +2682,8 @@ fn i32Shru() -> Vec<AbstractOp> {
 fn i64Shru() -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(40 as u8))));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Mod));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(63 as u8))));
+    result.push(AbstractOp::Op(Op::And));
     result.push(AbstractOp::Op(Op::Shr));
     result.push(AbstractOp::Op(Op::Push8(Imm::from(BYTES8))));
     result.push(AbstractOp::Op(Op::And));
@@ -1403,9 +2704,8 @@ fn Shr() -> Vec<AbstractOp> {
 fn i32Shl() -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(20 as u8))));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Mod));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(31 as u8))));
+    result.push(AbstractOp::Op(Op::And));
     result.push(AbstractOp::Op(Op::Shl));
     result.push(AbstractOp::Op(Op::Push4(Imm::from(BYTES4))));
     result.push(AbstractOp::Op(Op::And));
@@ -1416,9 +2716,8 @@ fn i32Shl() -> Vec<AbstractOp> {
 fn i64Shl() -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Push1(Imm::from(40 as u8))));
-    result.push(AbstractOp::Op(Op::Swap1));
-    result.push(AbstractOp::Op(Op::Mod));
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(63 as u8))));
+    result.push(AbstractOp::Op(Op::And));
     result.push(AbstractOp::Op(Op::Shl));
     result.push(AbstractOp::Op(Op::Push8(Imm::from(BYTES8))));
     result.push(AbstractOp::Op(Op::And));
@@ -1442,10 +2741,24 @@ fn unreachable() -> Vec<AbstractOp> {
     result
 }
 
+/// Pushes the EVM memory offset of local `idx` in the current frame.
+fn local_address(idx: u32) -> Vec<AbstractOp> {
+    let mut result: Vec<AbstractOp> = Vec::new();
+
+    result.push(AbstractOp::Op(Op::Push1(Imm::from(layout::FRAME_POINTER as u8))));
+    result.push(AbstractOp::Op(Op::MLoad));
+    if idx > 0 {
+        result.push(AbstractOp::Op(Op::Push4(Imm::from(layout::local(idx) as u32))));
+        result.push(AbstractOp::Op(Op::Add));
+    }
+
+    result
+}
+
 fn Local_get(idx: &u32) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Push4(Imm::from(idx * 0x20 as u32))));
+    result.append(local_address(*idx).as_mut());
     result.push(AbstractOp::Op(Op::MLoad));
 
     result
@@ -1454,7 +2767,7 @@ fn Local_get(idx: &u32) -> Vec<AbstractOp> {
 fn Local_set(idx: &u32) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Push4(Imm::from(idx * 0x20 as u32))));
+    result.append(local_address(*idx).as_mut());
     result.push(AbstractOp::Op(Op::MStore));
 
     result
@@ -1464,65 +2777,1072 @@ fn Local_tee(idx: &u32) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
     result.push(AbstractOp::Op(Op::Dup1));
-    result.push(AbstractOp::Op(Op::Push4(Imm::from(idx * 0x20 as u32))));
+    result.append(local_address(*idx).as_mut());
     result.push(AbstractOp::Op(Op::MStore));
 
     result
 }
 
-fn br_if(context: &Context, idx: &u32) -> Vec<AbstractOp> {
+fn br_if(context: &mut Context, idx: &u32) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
+    let target = label(context, idx);
 
-    result.push(AbstractOp::Op(Op::Push2(Imm::with_label(
-        context.labels.get(*idx as usize).unwrap(),
-    ))));
-    result.push(AbstractOp::Op(Op::JumpI));
+    if excess(context, target) == 0 {
+        result.push(AbstractOp::Op(Op::Push2(Imm::with_label(&target.name))));
+        result.push(AbstractOp::Op(Op::JumpI));
+    } else {
+        let skip = context.fresh_label();
+        result.push(AbstractOp::Op(Op::IsZero));
+        result.push(AbstractOp::Op(Op::Push2(Imm::with_label(&skip))));
+        result.push(AbstractOp::Op(Op::JumpI));
+        result.append(branch(context, idx).as_mut());
+        result.push(AbstractOp::Label(skip.clone()));
+        result.push(AbstractOp::Op(Op::JumpDest));
+    }
 
     result
 }
 
 fn br(context: &Context, idx: &u32) -> Vec<AbstractOp> {
-    let mut result: Vec<AbstractOp> = Vec::new();
-
-    result.push(AbstractOp::Op(Op::Push2(Imm::with_label(
-        context.labels.get(*idx as usize).unwrap(),
-    ))));
-    result.push(AbstractOp::Op(Op::Jump));
-
-    result
+    branch(context, idx)
 }
 
 fn drop() -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
 
-    result.push(AbstractOp::Op(Op::Push4(Imm::from(BYTES4))));
+    result.push(AbstractOp::Op(Op::Pop));
 
     result
 }
 
-fn select() -> Vec<AbstractOp> {
+/// Keeps the first operand if the condition on top is nonzero, else the second.
+fn select(context: &mut Context) -> Vec<AbstractOp> {
     let mut result: Vec<AbstractOp> = Vec::new();
-    let mut rng = rand::thread_rng();
-    let random_nonzero: u32 = rng.gen();
-    result.push(AbstractOp::Op(Op::Push2(Imm::with_label(
-        random_nonzero.to_string(),
-    ))));
+    let nonzero = context.fresh_label();
+    let exit = context.fresh_label();
+
+    result.push(AbstractOp::Op(Op::Push2(Imm::with_label(&nonzero))));
     result.push(AbstractOp::Op(Op::JumpI));
+    result.push(AbstractOp::Op(Op::Swap1));
     result.push(AbstractOp::Op(Op::Pop));
-
-    let random_exit: u32 = rng.gen();
-    result.push(AbstractOp::Op(Op::Push2(Imm::with_label(
-        random_exit.to_string(),
-    ))));
+    result.push(AbstractOp::Op(Op::Push2(Imm::with_label(&exit))));
     result.push(AbstractOp::Op(Op::Jump));
 
-    result.push(AbstractOp::Label(random_nonzero.to_string()));
+    result.push(AbstractOp::Label(nonzero));
     result.push(AbstractOp::Op(Op::JumpDest));
-    result.push(AbstractOp::Op(Op::Swap1));
     result.push(AbstractOp::Op(Op::Pop));
 
-    result.push(AbstractOp::Label(random_exit.to_string()));
+    result.push(AbstractOp::Label(exit));
     result.push(AbstractOp::Op(Op::JumpDest));
 
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use revm_primitives::{Halt, Output};
+    use wain_exec::{DefaultImporter, Machine};
+
+    /// Compiles `source` with one contract per export. The module is leaked so
+    /// that the runner borrowing it can be handed back.
+    fn instantiate(source: &str) -> Runner<'static, 'static> {
+        let binary: &'static [u8] = Box::leak(wat::parse_str(source).unwrap().into_boxed_slice());
+        let tree = Box::leak(Box::new(parse(binary).ok().unwrap()));
+        Runner::instantiate(&tree.module).ok().unwrap()
+    }
+
+    /// Whether the call ended in the trap handler.
+    fn trapped(runner: &mut Runner, name: &str, args: &[Value]) -> bool {
+        matches!(
+            runner.invoke(name, args).unwrap(),
+            ExecutionResult::Halt {
+                reason: Halt::InvalidFEOpcode,
+                ..
+            }
+        )
+    }
+
+    fn call(runner: &mut Runner, name: &str, args: &[Value]) -> U256 {
+        match runner.invoke(name, args).unwrap() {
+            ExecutionResult::Success {
+                output: Output::Call(bytes),
+                ..
+            } => U256::from_big_endian(&bytes),
+            other => panic!("{} failed: {:?}", name, other),
+        }
+    }
+
+    /// Runs the export `name` on every list of operands both compiled to EVM
+    /// and in the `wain-exec` interpreter, and checks that they agree. Operands
+    /// are given as 64-bit patterns and truncated for i32 params.
+    fn compare_with_interpreter(source: &str, name: &str, operands: &[Vec<i64>]) {
+        let binary = wat::parse_str(source).unwrap();
+        let tree = parse(&binary).ok().unwrap();
+        let mut runner = Runner::instantiate(&tree.module).ok().unwrap();
+        let importer = DefaultImporter::with_stdio(std::io::empty(), std::io::sink());
+        let mut machine = Machine::instantiate(&tree.module, importer).ok().unwrap();
+        let idx = tree
+            .module
+            .exports
+            .iter()
+            .find_map(|export| match export.kind {
+                ExportKind::Func(idx) if export.name.0 == name => Some(idx),
+                _ => None,
+            })
+            .unwrap();
+        let params = &ModuleInfo::new(&tree.module).func_type(idx).params;
+
+        for operands in operands {
+            let (args, evm_args): (Vec<_>, Vec<_>) = operands
+                .iter()
+                .zip(params.iter())
+                .map(|(&v, ty)| match ty {
+                    ValType::I32 => (wain_exec::Value::I32(v as i32), Value::I32(v as i32)),
+                    _ => (wain_exec::Value::I64(v), Value::I64(v)),
+                })
+                .unzip();
+            let expected = match machine.invoke(name, &args).ok().unwrap() {
+                Some(wain_exec::Value::I32(v)) => U256::from(v as u32),
+                Some(wain_exec::Value::I64(v)) => U256::from(v as u64),
+                _ => panic!("{} has no integer result", name),
+            };
+            assert_eq!(call(&mut runner, name, &evm_args), expected, "{}({:x?})", name, operands);
+        }
+    }
+
+    fn all_pairs(values: &[i64], counts: &[i64]) -> Vec<Vec<i64>> {
+        values
+            .iter()
+            .flat_map(|&value| counts.iter().map(move |&count| vec![value, count]))
+            .collect()
+    }
+
+    #[test]
+    fn loads_and_stores_match_the_interpreter() {
+        // loads read back `value` stored one byte past `addr`; stores write
+        // `value` over `fill` and read the whole word back
+        let loads = [
+            ("i32", "i32.load8_s"),
+            ("i32", "i32.load8_u"),
+            ("i32", "i32.load16_s"),
+            ("i32", "i32.load16_u"),
+            ("i32", "i32.load"),
+            ("i64", "i64.load8_s"),
+            ("i64", "i64.load8_u"),
+            ("i64", "i64.load16_s"),
+            ("i64", "i64.load16_u"),
+            ("i64", "i64.load32_s"),
+            ("i64", "i64.load32_u"),
+            ("i64", "i64.load"),
+        ];
+        let stores = [
+            ("i32", "i32.store8"),
+            ("i32", "i32.store16"),
+            ("i32", "i32.store"),
+            ("i64", "i64.store8"),
+            ("i64", "i64.store16"),
+            ("i64", "i64.store32"),
+            ("i64", "i64.store"),
+        ];
+        let addresses = [0, 7, 1000, 0xfff0];
+        let values = [0, 0x7f, 0x80, 0x8000_0080, 0x0123_4567_89ab_cdef, -1, i64::MIN + 0xff];
+        let mut operands = all_pairs(&addresses, &values);
+        for (i, operand) in operands.iter_mut().enumerate() {
+            operand.push(if i % 2 == 0 { -1 } else { 0x5555_5555_5555_5555 });
+        }
+
+        for (ty, load) in loads {
+            let source = format!(
+                r#"(module (memory 1) (func (export "add") (param i32 i64) (result {ty})
+                     local.get 0 local.get 1 i64.store offset=1
+                     local.get 0 {load} offset=2))"#
+            );
+            compare_with_interpreter(&source, "add", &operands);
+        }
+        for (ty, store) in stores {
+            let source = format!(
+                r#"(module (memory 1) (func (export "add") (param i32 {ty} i64) (result i64)
+                     local.get 0 local.get 2 i64.store
+                     local.get 0 local.get 1 {store} offset=1
+                     local.get 0 i64.load))"#
+            );
+            compare_with_interpreter(&source, "add", &operands);
+        }
+    }
+
+    #[test]
+    fn out_of_bounds_accesses_trap() {
+        let module = |func: &str| instantiate(&format!(r#"(module (memory 1) (func (export "add") {}))"#, func));
+
+        let mut load = module("(param i32) (result i32) local.get 0 i32.load");
+        assert_eq!(call(&mut load, "add", &[Value::U32(0xfffc)]), U256::zero());
+        assert!(trapped(&mut load, "add", &[Value::U32(0xfffd)]));
+        assert!(trapped(&mut load, "add", &[Value::U32(u32::MAX)]));
+
+        let mut load_offset = module("(param i32) (result i32) local.get 0 i32.load offset=3");
+        assert_eq!(call(&mut load_offset, "add", &[Value::U32(0xfff9)]), U256::zero());
+        assert!(trapped(&mut load_offset, "add", &[Value::U32(0xfffa)]));
+        assert!(trapped(&mut load_offset, "add", &[Value::U32(-2i32 as u32)]));
+
+        let mut load8 = module("(param i32) (result i64) local.get 0 i64.load8_u");
+        assert_eq!(call(&mut load8, "add", &[Value::U32(0xffff)]), U256::zero());
+        assert!(trapped(&mut load8, "add", &[Value::U32(0x10000)]));
+
+        let mut store = module("(param i32 i64) (result i64) local.get 0 local.get 1 i64.store local.get 1");
+        assert_eq!(call(&mut store, "add", &[Value::U32(0xfff8), Value::U64(9)]), U256::from(9));
+        assert!(trapped(&mut store, "add", &[Value::U32(0xfff9), Value::U64(9)]));
+    }
+
+    #[test]
+    fn memory_grows_up_to_its_maximum() {
+        let mut grow = instantiate(
+            r#"(module (memory 1 3)
+              (func (export "add") (param i32 i32) (result i32) (local i32)
+                local.get 0 memory.grow local.set 2 local.get 1 memory.grow))"#,
+        );
+        let mut grow = |first: u32, second: u32| call(&mut grow, "add", &[Value::U32(first), Value::U32(second)]);
+        assert_eq!(grow(0, 0), U256::from(1));
+        assert_eq!(grow(1, 1), U256::from(2));
+        assert_eq!(grow(0, 2), U256::from(1));
+        assert_eq!(grow(2, 0), U256::from(3));
+        // failures return -1 and leave the size alone
+        assert_eq!(grow(0, 3), U256::from(u32::MAX));
+        assert_eq!(grow(2, 1), U256::from(u32::MAX));
+        assert_eq!(grow(0, u32::MAX), U256::from(u32::MAX));
+
+        let mut size = instantiate(
+            r#"(module (memory 1 3)
+              (func (export "add") (param i32) (result i32) (local i32)
+                local.get 0 memory.grow local.set 1 memory.size))"#,
+        );
+        assert_eq!(call(&mut size, "add", &[Value::U32(5)]), U256::from(1));
+        assert_eq!(call(&mut size, "add", &[Value::U32(2)]), U256::from(3));
+
+        // grown pages can be used, and every call starts from the initial size
+        let mut touch = instantiate(
+            r#"(module (memory 1 3)
+              (func (export "add") (param i32 i32) (result i32) (local i32)
+                local.get 0 memory.grow local.set 2
+                local.get 1 local.get 1 i32.store local.get 1 i32.load))"#,
+        );
+        assert!(trapped(&mut touch, "add", &[Value::U32(0), Value::U32(70000)]));
+        assert_eq!(call(&mut touch, "add", &[Value::U32(1), Value::U32(70000)]), U256::from(70000));
+        assert!(trapped(&mut touch, "add", &[Value::U32(0), Value::U32(70000)]));
+
+        // without a maximum, the 4 GiB of 32-bit addresses are the limit
+        let mut unbounded = instantiate(
+            r#"(module (memory 1) (func (export "add") (param i32) (result i32) local.get 0 memory.grow))"#,
+        );
+        assert_eq!(call(&mut unbounded, "add", &[Value::U32(0xffff)]), U256::from(1));
+        assert_eq!(call(&mut unbounded, "add", &[Value::U32(0x10000)]), U256::from(u32::MAX));
+    }
+
+    #[test]
+    fn data_segments_land_where_the_interpreter_puts_them() {
+        // later segments overwrite earlier ones, and the empty one is skipped
+        let segments = r#"(memory 1)
+          (data (i32.const 0) "\01\02\03\04\05\06\07\08")
+          (data (i32.const 3) "\aa\bb")
+          (data (i32.const 100) "")
+          (data (i32.const 100) "hello")
+          (data (i32.const 65534) "\fe\ff")"#;
+        let addresses: Vec<Vec<i64>> = [0, 1, 2, 3, 4, 5, 7, 8, 99, 100, 104, 105, 65532, 65534, 65535]
+            .iter()
+            .map(|&a| vec![a])
+            .collect();
+
+        let byte = format!(
+            r#"(module {} (func (export "add") (param i32) (result i32) local.get 0 i32.load8_u))"#,
+            segments
+        );
+        compare_with_interpreter(&byte, "add", &addresses);
+        let word = format!(
+            r#"(module {} (func (export "add") (param i32) (result i32) local.get 0 i32.load))"#,
+            segments
+        );
+        compare_with_interpreter(&word, "add", &addresses[..addresses.len() - 2]);
+    }
+
+    #[test]
+    fn recursive_calls_keep_their_frames() {
+        // sum(n) = n + sum(n - 1), with the 1 passed in as `one`
+        let source = r#"(module
+          (func $sum (export "add") (param $n i32) (param $one i32) (result i32) (local $r i32)
+            local.get $n local.get $n i32.sub local.set $r
+            (block $done
+              local.get $n i32.eqz br_if $done
+              local.get $n
+              local.get $n local.get $one i32.sub local.get $one call $sum
+              i32.add local.set $r)
+            local.get $r))"#;
+        let operands: Vec<Vec<i64>> = [0, 1, 2, 10, 100].iter().map(|&n| vec![n, 1]).collect();
+
+        compare_with_interpreter(source, "add", &operands);
+    }
+
+    #[test]
+    fn calls_leave_the_values_under_their_arguments() {
+        // k + ((b - a) - a * k) + a * k, with the caller's local 3 intact after the calls
+        let source = r#"(module
+          (func $sub (param i32 i32) (result i32) local.get 0 local.get 1 i32.sub)
+          (func (export "add") (param $a i32) (param $b i32) (param $k i32) (result i32) (local i32)
+            local.get $a local.get $k i32.mul local.set 3
+            local.get $k
+            local.get $b local.get $a call $sub
+            local.get 3 call $sub
+            i32.add
+            local.get 3 i32.add))"#;
+
+        compare_with_interpreter(source, "add", &[vec![3, 50, 10], vec![0, 0, 1000], vec![7, 2, 5]]);
+    }
+
+    #[test]
+    fn running_out_of_frames_traps() {
+        let mut runner = instantiate(&format!(
+            r#"(module (func $deep (export "add") (param i32) (result i32) {}
+                 local.get 0 call $deep))"#,
+            "(local i64)".repeat(64)
+        ));
+        assert!(trapped(&mut runner, "add", &[Value::U32(0)]));
+    }
+
+    #[test]
+    fn if_else_matches_the_interpreter() {
+        let source = r#"(module
+          (func (export "pick") (param i32 i32 i32) (result i32)
+            local.get 0 if (result i32) local.get 1 else local.get 2 end)
+          (func (export "no_else") (param i32 i32 i32) (result i32) (local i32)
+            local.get 1 local.set 3
+            local.get 0 if local.get 2 local.set 3 end
+            local.get 3)
+          (func (export "nested") (param i32 i32 i32) (result i32)
+            local.get 0
+            if (result i32)
+              local.get 1
+              if (result i32) local.get 1 else local.get 2 end
+            else
+              local.get 2 local.get 1 i32.add
+            end
+            local.get 0 i32.add))"#;
+        let values = [0, 1, 7, 100, 0xffff_ffff];
+        let mut operands = Vec::new();
+        for &a in &values {
+            for &b in &values {
+                operands.push(vec![a, b, 1000 - b]);
+            }
+        }
+
+        for name in ["pick", "no_else", "nested"] {
+            compare_with_interpreter(source, name, &operands);
+        }
+    }
+
+    #[test]
+    fn br_table_matches_the_interpreter() {
+        // `chain` has few enough labels for the comparisons, `jump` goes
+        // through the code table; each target returns another param
+        let source = r#"(module
+          (func (export "chain") (param i32 i32 i32 i32) (result i32)
+            (block $d (block $b2 (block $b1 (block $b0
+              local.get 0 br_table $b0 $b1 $b2 $d)
+              local.get 1 return) local.get 2 return) local.get 3 return)
+            local.get 1 local.get 2 i32.add)
+          (func (export "jump") (param i32 i32 i32 i32) (result i32)
+            (block $d (block $b3 (block $b2 (block $b1 (block $b0
+              local.get 0 br_table $b0 $b1 $b2 $b3 $b0 $b1 $b2 $d)
+              local.get 1 return) local.get 2 return) local.get 3 return)
+              local.get 2 local.get 3 i32.add return)
+            local.get 1 local.get 2 i32.add))"#;
+        let operands: Vec<Vec<i64>> = [0, 1, 2, 3, 4, 5, 6, 7, 8, 100, -1, i32::MIN as i64]
+            .iter()
+            .map(|&i| vec![i, 10, 200, 3000])
+            .collect();
+
+        compare_with_interpreter(source, "chain", &operands);
+        compare_with_interpreter(source, "jump", &operands);
+    }
+
+    #[test]
+    fn branches_unwind_like_the_interpreter() {
+        // every function takes `[x, y, z]` and leaves values under its branches
+        let source = r#"(module
+          (func (export "br_if_excess") (param i32 i32 i32) (result i32)
+            (block $b (result i32)
+              local.get 1 local.get 2
+              local.get 2 local.get 0 br_if $b
+              i32.add i32.add)
+            local.get 1 i32.add)
+          (func (export "br_nested") (param i32 i32 i32) (result i32)
+            (block $outer (result i32)
+              local.get 1
+              (block $mid (result i32)
+                local.get 2
+                (block $inner
+                  local.get 0 local.get 1 i32.add
+                  local.get 0 br_if $outer
+                  drop))
+              i32.add))
+          (func $ret_junk (export "ret_junk") (param i32 i32 i32) (result i32)
+            local.get 1 local.get 2
+            (block (result i32) local.get 2 local.get 0 return)
+            i32.add i32.add)
+          (func (export "calls_ret_junk") (param i32 i32 i32) (result i32)
+            local.get 1 local.get 0 local.get 1 local.get 2 call $ret_junk i32.add)
+          (func (export "br_to_join") (param i32 i32 i32) (result i32)
+            local.get 0
+            if (result i32) local.get 1 local.get 2 br 0 else local.get 1 end
+            local.get 2 i32.add)
+          (func (export "br_past_if") (param i32 i32 i32) (result i32)
+            (block $b (result i32)
+              local.get 1
+              local.get 0 if local.get 2 br $b end))
+          (func (export "br_from_else") (param i32 i32 i32) (result i32)
+            (block $b (result i32)
+              local.get 0
+              if (result i32) local.get 1 else local.get 1 local.get 2 br $b end
+              local.get 1 i32.add))
+          (func (export "br_out_of_arms") (param i32 i32 i32) (result i32) (local i32)
+            local.get 1 local.set 3
+            (block $b
+              local.get 0
+              if local.get 2 local.set 3 br $b else br $b end
+              local.get 0 local.set 3)
+            local.get 3)
+          (func (export "chain_carry") (param i32 i32 i32) (result i32)
+            (block $out (result i32)
+              (block $a (result i32)
+                (block $b (result i32)
+                  local.get 1 local.get 2 local.get 0
+                  br_table $b $a $out)
+                local.get 1 i32.add br $out)
+              local.get 2 i32.add))
+          (func (export "jump_carry") (param i32 i32 i32) (result i32)
+            (block $out (result i32)
+              (block $a (result i32)
+                (block $b (result i32)
+                  local.get 1 local.get 2 local.get 0
+                  br_table $b $a $out $b $a $out $b)
+                local.get 1 i32.add br $out)
+              local.get 2 i32.add))
+          (func (export "loop_junk") (param i32 i32 i32) (result i32) (local i32)
+            (loop $l
+              local.get 2
+              local.get 3 local.get 0 i32.add local.set 3
+              local.get 0 local.get 1 i32.sub local.tee 0
+              br_if $l
+              drop)
+            local.get 3))"#;
+        let operands: Vec<Vec<i64>> = [0, 1, 2, 5, 10, -3].iter().map(|&v| vec![v, 100, 20000]).collect();
+
+        for name in [
+            "br_if_excess",
+            "br_nested",
+            "ret_junk",
+            "calls_ret_junk",
+            "br_to_join",
+            "br_past_if",
+            "br_from_else",
+            "br_out_of_arms",
+            "chain_carry",
+            "jump_carry",
+        ] {
+            compare_with_interpreter(source, name, &operands);
+        }
+        // counts down from x in steps of y
+        compare_with_interpreter(source, "loop_junk", &[vec![1, 1, 7], vec![5, 1, 7], vec![10, 2, 7]]);
+    }
+
+    /// The code of every function body in `module`, labels included.
+    fn bodies(module: &Module) -> Vec<AbstractOp> {
+        let info = ModuleInfo::new(module);
+        let imports = Imports::default();
+        let mut ops = Vec::new();
+        for (idx, func) in info.funcs() {
+            if let FuncKind::Body { locals, expr } = &func.kind {
+                let mut context = Context::new(info, &Globals::Memory, &imports, idx, locals);
+                ops.append(function_body(idx, expr, &mut context).as_mut());
+            }
+        }
+        ops
+    }
+
+    #[test]
+    fn compilation_is_reproducible() {
+        let source = r#"(module (memory 1)
+          (func $f (export "f") (param i32 i32) (result i32)
+            (block $a (block $b (block $c (block $d (block $e
+              local.get 0 br_table $a $b $c $d $e $a)))))
+            (loop $l (result i32)
+              local.get 0 local.get 1 i32.sub local.tee 0
+              br_if $l
+              local.get 0 local.get 1 local.get 0 select)
+            if (result i32) local.get 0 else local.get 1 end)
+          (func (export "g") (param i32 i32) (result i32) local.get 0 local.get 1 call $f))"#;
+        let binary = wat::parse_str(source).unwrap();
+        let tree = parse(&binary).ok().unwrap();
+
+        // the same labels on every run, none of them defined twice
+        let ops = bodies(&tree.module);
+        assert_eq!(format!("{:?}", ops), format!("{:?}", bodies(&tree.module)));
+        let mut labels: Vec<&String> = ops
+            .iter()
+            .filter_map(|op| match op {
+                AbstractOp::Label(label) => Some(label),
+                _ => None,
+            })
+            .collect();
+        let count = labels.len();
+        labels.sort();
+        labels.dedup();
+        assert_eq!(labels.len(), count);
+
+        let code = || {
+            let runner = instantiate(source);
+            let mut hashes: Vec<String> =
+                runner.db.contracts.keys().map(|hash| format!("{:?}", hash)).collect();
+            hashes.sort();
+            (hashes, runner.functions)
+        };
+        // one runtime per export next to the empty code
+        let (hashes, functions) = code();
+        assert!(hashes.len() > functions.len(), "{:?}", hashes);
+        assert_eq!((hashes, functions), code());
+    }
+
+    #[test]
+    fn constants_match_the_interpreter() {
+        // constants around every PUSHn boundary, used as they are and in arithmetic
+        let constants: [i64; 14] = [
+            0, 1, 0xff, 0x100, 0xffff, 0x1_0000, 0x7fff_ffff, -1, -2, -0x80, -0x8000_0000,
+            0x1_0000_0000, i64::MAX, i64::MIN,
+        ];
+        let mut source = String::from("(module");
+        for (i, c) in constants.iter().enumerate() {
+            source += &format!(
+                r#"(func (export "i32 {i}") (param i32) (result i32) i32.const {c32} local.get 0 i32.add)
+                   (func (export "i64 {i}") (param i64) (result i64) i64.const {c} local.get 0 i64.add)"#,
+                c32 = *c as i32
+            );
+        }
+        source += ")";
+        let operands: Vec<Vec<i64>> = [0, 1, -1].iter().map(|&v| vec![v]).collect();
+
+        for i in 0..constants.len() {
+            compare_with_interpreter(&source, &format!("i32 {}", i), &operands);
+            compare_with_interpreter(&source, &format!("i64 {}", i), &operands);
+        }
+    }
+
+    #[test]
+    fn call_indirect_checks_the_element() {
+        let mut runner = instantiate(
+            r#"(module
+              (type $t0 (func (param i32) (result i32)))
+              (type $t1 (func (param i32) (result i32)))
+              (type $t2 (func (param i64) (result i64)))
+              (import "env" "sload" (func $sload (type $t2)))
+              (import "env" "sstore" (func $sstore (param i64 i64)))
+              (table 6 funcref)
+              (func $inc (type $t0) local.get 0 i32.const 1 i32.add)
+              (func $dbl (type $t1) local.get 0 i32.const 2 i32.mul)
+              (func $wide (type $t2) local.get 0 i64.const 3 i64.add)
+              (elem (i32.const 0) $inc $dbl $wide)
+              (elem (i32.const 4) $inc $sload)
+              (func (export "narrow") (param i32 i32) (result i32)
+                local.get 1 local.get 0 call_indirect (type $t0))
+              (func (export "wide") (param i32 i64) (result i64)
+                local.get 1 i64.const 99 call $sstore
+                local.get 1 local.get 0 call_indirect (type $t2)))"#,
+        );
+        let narrow = |runner: &mut Runner, idx: u32| call(runner, "narrow", &[Value::U32(idx), Value::U32(5)]);
+
+        // $dbl is declared with $t1, which has the same signature as $t0
+        assert_eq!(narrow(&mut runner, 0), U256::from(6));
+        assert_eq!(narrow(&mut runner, 1), U256::from(10));
+        assert_eq!(narrow(&mut runner, 4), U256::from(6));
+        assert_eq!(call(&mut runner, "wide", &[Value::U32(2), Value::U64(7)]), U256::from(10));
+        // the element for the import runs its binding
+        assert_eq!(call(&mut runner, "wide", &[Value::U32(5), Value::U64(7)]), U256::from(99));
+        // signature mismatches both ways, with a defined function and with an import
+        assert!(trapped(&mut runner, "narrow", &[Value::U32(2), Value::U32(5)]));
+        assert!(trapped(&mut runner, "narrow", &[Value::U32(5), Value::U32(5)]));
+        assert!(trapped(&mut runner, "wide", &[Value::U32(0), Value::U64(7)]));
+        // an empty element and indices past the table
+        for idx in [3, 6, 7, u32::MAX] {
+            assert!(trapped(&mut runner, "narrow", &[Value::U32(idx), Value::U32(5)]), "{}", idx);
+        }
+    }
+
+    #[test]
+    fn types_past_the_null_type_are_an_error() {
+        // type 0xffff would read as the empty table element
+        let source = format!(
+            r#"(module {} (func (export "f") (result i32) i32.const 7))"#,
+            "(type (func)) ".repeat(MAX_TYPES)
+        );
+        let binary = wat::parse_str(&source).unwrap();
+        let tree = parse(&binary).ok().unwrap();
+        match Runner::instantiate(&tree.module) {
+            Err(trap) => assert!(matches!(trap.reason, TrapReason::OutOfLimit { kind: "type", .. })),
+            Ok(_) => panic!("{} types were accepted", tree.module.types.len()),
+        }
+    }
+
+    fn words(result: ExecutionResult) -> Vec<U256> {
+        match result {
+            ExecutionResult::Success {
+                output: Output::Call(bytes),
+                ..
+            } => bytes.chunks(32).map(U256::from_big_endian).collect(),
+            other => panic!("call failed: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn void_and_multi_value_returns() {
+        let mut runner = instantiate(
+            r#"(module (memory 1)
+              (func $three (param i32) (result i32 i64 i32)
+                i32.const 99
+                (block (result i32) local.get 0 i64.const -2 i32.const 3 return)
+                drop drop i64.const 0 i32.const 0)
+              (func (export "three") (param i32) (result i32 i64 i32) local.get 0 call $three)
+              (func (export "sum") (param i32) (result i64) (local i64)
+                local.get 0 call $three
+                i64.extend_i32_u i64.add
+                local.set 1 drop local.get 1)
+              (func (export "void") (param i32) local.get 0 local.get 0 i32.store)
+              (func (export "nothing")))"#,
+        );
+
+        let three = words(runner.invoke("three", &[Value::U32(-5i32 as u32)]).unwrap());
+        assert_eq!(
+            three,
+            vec![U256::from(-5i32 as u32), U256::from(-2i64 as u64), U256::from(3)]
+        );
+        assert_eq!(words(runner.invoke("sum", &[Value::U32(7)]).unwrap()), vec![U256::from(1)]);
+        assert_eq!(words(runner.invoke("void", &[Value::U32(8)]).unwrap()), vec![]);
+        assert_eq!(words(runner.invoke("nothing", &[]).unwrap()), vec![]);
+    }
+
+    #[test]
+    fn signatures_come_from_the_type_index() {
+        // the imports shift the function indices, and the types are declared in
+        // another order than the functions using them
+        let mut runner = instantiate(
+            r#"(module
+              (type $wide (func (param i64 i64) (result i64)))
+              (type $unary (func (param i32) (result i32)))
+              (type $void (func))
+              (import "env" "sload" (func $sload (param i64) (result i64)))
+              (import "env" "sstore" (func $sstore (param i64 i64)))
+              (func $noop (type $void))
+              (func $neg (type $unary) i32.const 0 local.get 0 i32.sub)
+              (func $twice (type $unary) local.get 0 call $neg call $neg local.get 0 i32.add)
+              (func $put (type $wide) local.get 0 local.get 1 call $sstore local.get 0 call $sload)
+              (export "twice" (func $twice))
+              (export "put" (func $put))
+              (export "noop" (func $noop)))"#,
+        );
+
+        assert_eq!(call(&mut runner, "twice", &[Value::U32(21)]), U256::from(42));
+        assert_eq!(
+            call(&mut runner, "put", &[Value::U64(3), Value::U64(-9i64 as u64)]),
+            U256::from(-9i64 as u64)
+        );
+        assert_eq!(words(runner.invoke("noop", &[]).unwrap()), vec![]);
+    }
+
+    #[test]
+    fn locals_start_at_zero_in_a_dirty_frame() {
+        // $dirty and $fresh get the same frame, one after the other
+        let mut runner = instantiate(
+            r#"(module
+              (func $dirty (param i32) (local i32 i64 i32)
+                local.get 0 local.set 1
+                i64.const -1 local.set 2
+                local.get 0 local.set 3)
+              (func $fresh (param i32) (result i64) (local i32 i64 i32)
+                local.get 1 i64.extend_i32_u
+                local.get 2 i64.or
+                local.get 3 i64.extend_i32_u i64.or)
+              (func (export "f") (param i32) (result i64)
+                local.get 0 call $dirty
+                local.get 0 call $fresh)
+              (func $count (param i32) (result i32) (local i32)
+                local.get 1 i32.const 1 i32.add local.set 1
+                local.get 0 if (result i32)
+                  local.get 0 i32.const 1 i32.sub call $count local.get 1 i32.add
+                else
+                  local.get 1
+                end)
+              (func (export "count") (param i32) (result i32) local.get 0 call $count))"#,
+        );
+
+        assert_eq!(call(&mut runner, "f", &[Value::U32(77)]), U256::zero());
+        // every level sees its own local start at 0
+        assert_eq!(call(&mut runner, "count", &[Value::U32(5)]), U256::from(6));
+    }
+
+    #[test]
+    fn comparisons_match_the_interpreter() {
+        let mut source = String::from("(module");
+        for ty in ["i32", "i64"] {
+            for op in ["eq", "ne", "lt_s", "lt_u", "gt_s", "gt_u", "le_s", "le_u", "ge_s", "ge_u"] {
+                source += &format!(
+                    r#"(func (export "{ty}.{op}") (param {ty} {ty}) (result i32)
+                         local.get 0 local.get 1 {ty}.{op})"#
+                );
+            }
+            source += &format!(
+                r#"(func (export "{ty}.eqz") (param {ty} {ty}) (result i32) local.get 0 {ty}.eqz)"#
+            );
+        }
+        source += r#"(func (export "i64.extend_i32_s") (param i64 i64) (result i64)
+                       local.get 0 i32.wrap_i64 i64.extend_i32_s)
+                     (func (export "i64.extend_i32_u") (param i64 i64) (result i64)
+                       local.get 0 i32.wrap_i64 i64.extend_i32_u))"#;
+        let values = [
+            0, 1, -1, 0x7fff_ffff, i32::MIN as i64, 0xffff_ffff, 0x1_0000_0000, i64::MAX, i64::MIN,
+        ];
+        let operands = all_pairs(&values, &values);
+
+        for ty in ["i32", "i64"] {
+            for op in ["eq", "ne", "eqz", "lt_s", "lt_u", "gt_s", "gt_u", "le_s", "le_u", "ge_s", "ge_u"] {
+                compare_with_interpreter(&source, &format!("{}.{}", ty, op), &operands);
+            }
+        }
+        compare_with_interpreter(&source, "i64.extend_i32_s", &operands);
+        compare_with_interpreter(&source, "i64.extend_i32_u", &operands);
+    }
+
+    #[test]
+    fn rotates_match_the_interpreter() {
+        let source = r#"(module
+          (func (export "i32.rotl") (param i32 i32) (result i32) local.get 0 local.get 1 i32.rotl)
+          (func (export "i32.rotr") (param i32 i32) (result i32) local.get 0 local.get 1 i32.rotr)
+          (func (export "i64.rotl") (param i64 i64) (result i64) local.get 0 local.get 1 i64.rotl)
+          (func (export "i64.rotr") (param i64 i64) (result i64) local.get 0 local.get 1 i64.rotr))"#;
+        let values = [0, 1, 0x8123_4567, -0x7edc_ba99, i32::MIN as i64, -0x0123_4567_89ab_cdef, i64::MIN];
+        let counts32 = all_pairs(&values, &[0, 1, 7, 31, 32, 33, 100, -1]);
+        let counts64 = all_pairs(&values, &[0, 1, 7, 32, 63, 64, 65, 200, -1]);
+
+        compare_with_interpreter(source, "i32.rotl", &counts32);
+        compare_with_interpreter(source, "i32.rotr", &counts32);
+        compare_with_interpreter(source, "i64.rotl", &counts64);
+        compare_with_interpreter(source, "i64.rotr", &counts64);
+    }
+
+    #[test]
+    fn bit_counts_match_the_interpreter() {
+        let source = r#"(module
+          (func (export "i32.popcnt") (param i32) (result i32) local.get 0 i32.popcnt)
+          (func (export "i32.ctz") (param i32) (result i32) local.get 0 i32.ctz)
+          (func (export "i32.clz") (param i32) (result i32) local.get 0 i32.clz)
+          (func (export "i64.popcnt") (param i64) (result i64) local.get 0 i64.popcnt)
+          (func (export "i64.ctz") (param i64) (result i64) local.get 0 i64.ctz)
+          (func (export "i64.clz") (param i64) (result i64) local.get 0 i64.clz))"#;
+        let mut values = vec![0, 1, -1, 0x8000_0000, 0x7fff_ffff, 0x1_0000_0000, i64::MIN, i64::MAX];
+        values.extend((0..64).map(|bit| 1i64 << bit));
+        values.extend((0..64).map(|bit| -1i64 << bit));
+        values.extend([0x0123_4567_89ab_cdef, -0x0123_4567_89ab_cdf0, 0xf0f0_0f0f, 0x10_0000]);
+        let operands: Vec<_> = values.iter().map(|&v| vec![v]).collect();
+
+        for name in ["i32.popcnt", "i32.ctz", "i32.clz", "i64.popcnt", "i64.ctz", "i64.clz"] {
+            compare_with_interpreter(source, name, &operands);
+        }
+    }
+
+    /// xorshift64, so that the operands are random but the same on every run.
+    fn random_operands(seed: u64, count: usize) -> Vec<u64> {
+        let mut state = seed;
+        (0..count)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state
+            })
+            .collect()
+    }
+
+    #[test]
+    fn shifts_match_wrapping_shifts() {
+        let source = r#"(module
+          (func (export "i32.shl") (param i32 i32) (result i32) local.get 0 local.get 1 i32.shl)
+          (func (export "i32.shr_s") (param i32 i32) (result i32) local.get 0 local.get 1 i32.shr_s)
+          (func (export "i32.shr_u") (param i32 i32) (result i32) local.get 0 local.get 1 i32.shr_u)
+          (func (export "i64.shl") (param i64 i64) (result i64) local.get 0 local.get 1 i64.shl)
+          (func (export "i64.shr_s") (param i64 i64) (result i64) local.get 0 local.get 1 i64.shr_s)
+          (func (export "i64.shr_u") (param i64 i64) (result i64) local.get 0 local.get 1 i64.shr_u))"#;
+        let binary = wat::parse_str(source).unwrap();
+        let tree = parse(&binary).ok().unwrap();
+        let mut runner = Runner::instantiate(&tree.module).ok().unwrap();
+
+        // Every count up to twice the width, then random counts, each against
+        // a random value and the ones whose sign bit is set or clear.
+        let values = random_operands(0x9e37_79b9_7f4a_7c15, 24);
+        let mut counts: Vec<u64> = (0..128).collect();
+        counts.extend(random_operands(0x2545_f491_4f6c_dd1d, 32));
+        counts.extend([u32::MAX as u64, u64::MAX, 1 << 32, 1 << 63]);
+
+        for (i, &count) in counts.iter().enumerate() {
+            for value in [values[i % values.len()], u64::MAX, 1 << 63, 1 << 31, 0x7fff_ffff] {
+                let (a, b) = (value as u32, count as u32);
+                let args = [Value::I32(a as i32), Value::I32(b as i32)];
+                assert_eq!(call(&mut runner, "i32.shl", &args), U256::from(a.wrapping_shl(b)));
+                assert_eq!(call(&mut runner, "i32.shr_u", &args), U256::from(a.wrapping_shr(b)));
+                assert_eq!(
+                    call(&mut runner, "i32.shr_s", &args),
+                    U256::from((a as i32).wrapping_shr(b) as u32),
+                    "i32.shr_s({:#x}, {:#x})",
+                    a,
+                    b
+                );
+
+                let (a, b) = (value, count);
+                let args = [Value::I64(a as i64), Value::I64(b as i64)];
+                assert_eq!(call(&mut runner, "i64.shl", &args), U256::from(a.wrapping_shl(b as u32)));
+                assert_eq!(call(&mut runner, "i64.shr_u", &args), U256::from(a.wrapping_shr(b as u32)));
+                assert_eq!(
+                    call(&mut runner, "i64.shr_s", &args),
+                    U256::from((a as i64).wrapping_shr(b as u32) as u64),
+                    "i64.shr_s({:#x}, {:#x})",
+                    a,
+                    b
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn division_matches_the_interpreter_and_traps() {
+        let mut source = String::from("(module");
+        for ty in ["i32", "i64"] {
+            for op in ["div_s", "div_u", "rem_s", "rem_u"] {
+                source += &format!(
+                    r#"(func (export "{ty}.{op}") (param {ty} {ty}) (result {ty})
+                         local.get 0 local.get 1 {ty}.{op})"#
+                );
+            }
+        }
+        source += ")";
+        let values = [0, 1, -1, 2, -2, 7, -7, i32::MIN as i64, i32::MAX as i64, i64::MIN, i64::MAX];
+
+        for ty in ["i32", "i64"] {
+            let narrow = |v: i64| if ty == "i32" { v as i32 as i64 } else { v };
+            let min = narrow(if ty == "i32" { i32::MIN as i64 } else { i64::MIN });
+            let pairs: Vec<_> = all_pairs(&values, &values)
+                .into_iter()
+                .filter(|pair| narrow(pair[1]) != 0)
+                .collect();
+            let safe: Vec<_> = pairs
+                .iter()
+                .filter(|pair| (narrow(pair[0]), narrow(pair[1])) != (min, -1))
+                .cloned()
+                .collect();
+
+            for op in ["div_u", "rem_s", "rem_u"] {
+                compare_with_interpreter(&source, &format!("{}.{}", ty, op), &pairs);
+            }
+            compare_with_interpreter(&source, &format!("{}.div_s", ty), &safe);
+        }
+
+        let binary = wat::parse_str(&source).unwrap();
+        let tree = parse(&binary).ok().unwrap();
+        let mut runner = Runner::instantiate(&tree.module).ok().unwrap();
+        let panic = |code: u8| format!("4e487b71{:064x}", code);
+        let trap = |runner: &mut Runner, name: &str, args: &[Value]| match runner.invoke(name, args).unwrap() {
+            ExecutionResult::Revert { output, .. } => hex::encode(output),
+            other => panic!("{} did not trap: {:?}", name, other),
+        };
+
+        for op in ["div_s", "div_u", "rem_s", "rem_u"] {
+            let name = format!("i32.{}", op);
+            assert_eq!(trap(&mut runner, &name, &[Value::I32(5), Value::I32(0)]), panic(0x12));
+            let name = format!("i64.{}", op);
+            assert_eq!(trap(&mut runner, &name, &[Value::I64(5), Value::I64(0)]), panic(0x12));
+        }
+        let args = [Value::I32(i32::MIN), Value::I32(-1)];
+        assert_eq!(trap(&mut runner, "i32.div_s", &args), panic(0x11));
+        assert_eq!(call(&mut runner, "i32.rem_s", &args), U256::zero());
+        let args = [Value::I64(i64::MIN), Value::I64(-1)];
+        assert_eq!(trap(&mut runner, "i64.div_s", &args), panic(0x11));
+        assert_eq!(call(&mut runner, "i64.rem_s", &args), U256::zero());
+    }
+
+    #[test]
+    fn invalid_modules_are_rejected() {
+        for func in ["(result i32) i32.const 1 br 3", "(result i32) i32.add", "(param i32) local.get 0"] {
+            let source = format!(r#"(module (func (export "f") {}))"#, func);
+            let binary = wat::parse_str(&source).unwrap();
+            let tree = parse(&binary).ok().unwrap();
+            assert!(Runner::instantiate(&tree.module).is_err(), "{}", func);
+        }
+    }
+
+    #[test]
+    fn failed_deployment_is_an_error() {
+        // a constructor that reverts leaves the create without an address
+        let mut runner = instantiate(r#"(module (func (export "f") (result i32) i32.const 7))"#);
+        let constructor = [
+            AbstractOp::Op(Op::Push1(Imm::from(0u8))),
+            AbstractOp::Op(Op::Push1(Imm::from(0u8))),
+            AbstractOp::Op(Op::Revert),
+        ];
+        match runner.deploy(&constructor, Vec::new(), &[]) {
+            Ok(address) => panic!("a reverting create deployed {}", address),
+            Err(trap) => assert!(matches!(
+                trap.reason,
+                TrapReason::ImportFuncCallFail { ref name, .. } if name == "create"
+            )),
+        }
+    }
+
+
+    #[test]
+    fn aliased_exports_share_an_entry() {
+        let source = r#"(module
+          (func $f (param i32) (result i32) local.get 0 i32.const 1 i32.add)
+          (export "a" (func $f))
+          (export "b" (func $f)))"#;
+        let binary = wat::parse_str(source).unwrap();
+        let tree = parse(&binary).ok().unwrap();
+        let mut runner = Runner::instantiate_with(
+            &tree.module,
+            Deployment::Module(abi::Selector::Keccak),
+            Globals::Memory,
+            Imports::default(),
+        )
+        .ok()
+        .unwrap();
+
+        assert_eq!(call(&mut runner, "a", &[Value::I32(1)]), U256::from(2));
+        assert_eq!(call(&mut runner, "b", &[Value::I32(41)]), U256::from(42));
+    }
+
+    #[test]
+    fn colliding_selectors_are_an_error() {
+        // keccak256("f8491()") and keccak256("f130736()") both start with 62018627
+        let source = r#"(module
+          (func (export "f8491") (result i32) i32.const 1)
+          (func (export "f130736") (result i32) i32.const 2))"#;
+        let binary = wat::parse_str(source).unwrap();
+        let tree = parse(&binary).ok().unwrap();
+        let result = Runner::instantiate_with(
+            &tree.module,
+            Deployment::Module(abi::Selector::Keccak),
+            Globals::Memory,
+            Imports::default(),
+        );
+        match result {
+            Ok(_) => panic!("colliding selectors were dispatched"),
+            Err(trap) => assert!(trap.to_string().contains("0x62018627")),
+        }
+    }
+
+    #[test]
+    fn short_calldata_reverts() {
+        let source = r#"(module (func (export "f") (result i32) i32.const 7))"#;
+        let binary = wat::parse_str(source).unwrap();
+        let tree = parse(&binary).ok().unwrap();
+        let mut runner = Runner::instantiate_with(
+            &tree.module,
+            Deployment::Module(abi::Selector::Index),
+            Globals::Memory,
+            Imports::default(),
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(call(&mut runner, "f", &[]), U256::from(7));
+
+        // selector 0 is `f`, which must not run for calldata without a selector
+        let address = runner.functions["f"].clone();
+        for calldata in ["", "00", "000000"] {
+            let (result, _) = revm_run::call_contract(address.clone(), calldata.to_string(), runner.db.clone());
+            assert!(matches!(result, ExecutionResult::Revert { .. }), "{:?}", result);
+        }
+    }
+
+    #[test]
+    fn oversized_runtime_is_rejected() {
+        let source = format!(
+            r#"(module (memory 2) (data (i32.const 0) "{}")
+                 (func (export "f") (result i32) i32.const 69996 i32.load))"#,
+            "\\01\\02\\03\\04".repeat(17_500)
+        );
+        let binary = wat::parse_str(&source).unwrap();
+        let tree = parse(&binary).ok().unwrap();
+        match Runner::instantiate(&tree.module) {
+            Err(trap) => assert!(matches!(trap.reason, TrapReason::OutOfLimit { max: MAX_CODE_SIZE, .. })),
+            Ok(_) => panic!("a 70 000 byte runtime was deployed"),
+        }
+    }
+
+    #[test]
+    fn offsets_from_imported_globals_are_rejected() {
+        for segment in [
+            r#"(memory 1) (data (global.get $g) "x")"#,
+            r#"(table 1 funcref) (elem (global.get $g) $f)"#,
+        ] {
+            let source = format!(
+                r#"(module (import "env" "g" (global $g i32)) {} (func $f))"#,
+                segment
+            );
+            let binary = wat::parse_str(&source).unwrap();
+            let tree = parse(&binary).ok().unwrap();
+            match Runner::instantiate(&tree.module) {
+                Err(trap) => assert!(matches!(trap.reason, TrapReason::UnknownImport { .. })),
+                Ok(_) => panic!("{} was instantiated", segment),
+            }
+        }
+    }
+
+    #[test]
+    fn results_up_to_the_swap_limit() {
+        let values: Vec<String> = (1..=16).map(|i| format!("i32.const {}", i)).collect();
+        let source = format!(
+            r#"(module (func (export "f") (result {}) {}))"#,
+            "i32 ".repeat(16),
+            values.join(" ")
+        );
+        let binary = wat::parse_str(&source).unwrap();
+        let tree = parse(&binary).ok().unwrap();
+        let mut runner = Runner::instantiate(&tree.module).ok().unwrap();
+        let output = match runner.invoke("f", &[]).unwrap() {
+            ExecutionResult::Success {
+                output: Output::Call(bytes),
+                ..
+            } => bytes.to_vec(),
+            other => panic!("f failed: {:?}", other),
+        };
+        let words: Vec<U256> = output.chunks(32).map(U256::from_big_endian).collect();
+        assert_eq!(words, (1..=16).map(U256::from).collect::<Vec<_>>());
+
+        let source = r#"(module (func (export "f") (result i32 i32 i32 i32 i32 i32 i32 i32 i32
+                                                     i32 i32 i32 i32 i32 i32 i32 i32)
+                           unreachable))"#;
+        let binary = wat::parse_str(source).unwrap();
+        let tree = parse(&binary).ok().unwrap();
+        match Runner::instantiate(&tree.module) {
+            Err(trap) => assert!(matches!(trap.reason, TrapReason::OutOfLimit { max: 16, idx: 17, .. })),
+            Ok(_) => panic!("17 results were compiled"),
+        }
+    }
+
+    #[test]
+    fn frames_grow_with_the_largest_function() {
+        // 1 100 locals do not fit the default frames region even once
+        let source = r#"(module
+          (func $big (param i32) (result i32) (local i64)
+            (local i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32)
+            local.get 0 local.set 1099 local.get 1099 i32.const 1 i32.add)
+          (func (export "f") (param i32) (result i32) local.get 0 call $big))"#
+            .replace("(local i64)", &"(local i32)".repeat(1079));
+        let binary = wat::parse_str(&source).unwrap();
+        let tree = parse(&binary).ok().unwrap();
+        let mut runner = Runner::instantiate(&tree.module).ok().unwrap();
+        assert_eq!(call(&mut runner, "f", &[Value::I32(41)]), U256::from(42));
+    }
+}
@@ -3,13 +3,14 @@ use revm::db::CacheDB;
 use revm::{
     db::in_memory_db::{EmptyDB, InMemoryDB},
     interpreter::{
-        analysis::to_analysed, CallInputs, Contract, Gas, InstructionResult,
+        analysis::to_analysed, CallInputs, Gas, InstructionResult,
         Interpreter,
     },
     primitives::{Bytecode, LatestSpec, TransactTo},
     EVMData, Inspector, EVM,
 };
-use revm_primitives::ExecutionResult;
+use revm_primitives::{ExecutionResult, Output};
+use primitive_types::H256;
 struct Inspect {}
 
 impl Inspector<InMemoryDB> for Inspect {
@@ -45,8 +46,12 @@ impl Inspector<InMemoryDB> for Inspect {
         (InstructionResult::Continue, Gas::new(0), Bytes::new())
     }
 }
-use revm_primitives::create_address;
-pub fn deploy_contract(hex: String) -> (ExecutionResult, String, Option<CacheDB<EmptyDB>>) {
+/// Deploys `hex` on top of `db`, so several contracts can live in one database.
+/// The address is only there when the create succeeded.
+pub fn deploy_contract(
+    hex: String,
+    db: CacheDB<EmptyDB>,
+) -> (ExecutionResult, Option<String>, Option<CacheDB<EmptyDB>>) {
     let contract_data: Bytes = hex::decode(hex).unwrap().into();
     let mut evm: EVM<InMemoryDB> = revm::new();
     evm.env.tx.caller = "0x1000000000000000000000000000000000000000"
@@ -58,25 +63,29 @@ pub fn deploy_contract(hex: String) -> (ExecutionResult, String, Option<CacheDB<
     evm.env.tx.data = contract_data.clone();
 
     evm.env.cfg.perf_all_precompiles_have_balance = true;
-    evm.database(InMemoryDB::new(EmptyDB::default()));
+    evm.database(db);
 
     let env = evm.env.clone();
     evm.env.tx.nonce = Some(0);
     let result = evm.inspect_commit::<Inspect>(Inspect {}).unwrap();
-    let contract_address = create_address(evm.env.tx.caller, 0);
-    let contract = Contract {
-        address: contract_address,
-        ..Default::default()
+    let contract_address = match &result {
+        ExecutionResult::Success {
+            output: Output::Create(_, Some(address)),
+            ..
+        } => Some(format!("0x{:x}", address)),
+        _ => None,
     };
 
-    return (result, format!("0x{:x}", contract_address), evm.db);
+    return (result, contract_address, evm.db);
 }
 
+/// Calls the contract at `contract_address` and returns `db` with the changes
+/// of the call committed, so state carries over to the next call.
 pub fn call_contract(
     contract_address: String,
     data: String,
     db: CacheDB<EmptyDB>,
-) -> ExecutionResult {
+) -> (ExecutionResult, Option<CacheDB<EmptyDB>>) {
     let mut evm: EVM<InMemoryDB> = revm::new();
     evm.env.tx.caller = "0x1000000000000000000000000000000000000000"
         .parse()
@@ -87,5 +96,28 @@ pub fn call_contract(
     evm.env.cfg.perf_all_precompiles_have_balance = true;
     evm.database(db);
     let result = evm.inspect_commit::<Inspect>(Inspect {}).unwrap();
-    result
+    (result, evm.db)
+}
+
+/// A log emitted by a call.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub address: String,
+    pub topics: Vec<H256>,
+    pub data: Vec<u8>,
+}
+
+/// The logs of a successful call, in the order they were emitted.
+pub fn events(result: &ExecutionResult) -> Vec<Event> {
+    match result {
+        ExecutionResult::Success { logs, .. } => logs
+            .iter()
+            .map(|log| Event {
+                address: format!("0x{:x}", log.address),
+                topics: log.topics.iter().map(|topic| H256(topic.0)).collect(),
+                data: log.data.to_vec(),
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
 }